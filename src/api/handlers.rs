@@ -1,132 +1,900 @@
 use std::{
-    fs::{create_dir_all, read_dir, DirEntry},
-    io::Cursor,
+    convert::Infallible,
+    fs::{create_dir_all, read_dir, remove_dir_all, DirEntry, File},
+    io::BufReader,
     path::Path,
+    str::FromStr,
     thread,
 };
 
-use axum::{extract::Multipart, http::StatusCode, response::Response, routing::post, Router};
+use axum::{
+    body::StreamBody,
+    extract::{multipart::Field, Multipart, Path as UrlPath, Query},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
 use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tokio_util::io::ReaderStream;
 use tracing::error;
+use uuid::Uuid;
 
-use crate::utils::{commands::install_bundle_all, env_helper::ENV_DATA};
+use crate::{
+    artifacts::{
+        apks_cache,
+        metadata::{self, BundleMetadata},
+        store::{self as artifact_store, Artifact},
+    },
+    device_adapter::i_adapter::{Device, DeviceFilter, DeviceStatus, InstallMode, OsType},
+    jobs::{
+        events,
+        job::{Job, JobStatus, UpgradePlan},
+        queue::{self, QueueSnapshot},
+        registry::{self, CancelError},
+    },
+    utils::{
+        bundle_kind::BundleKind,
+        commands::{find_devices, install_bundle_all, setup_flow_path, upgrade_bundle_all},
+        env_helper::ENV_DATA,
+    },
+};
 
 /// Initializes a new instance of [Router] to handle the rest APIs
 pub fn initialize_router() -> Router {
-    Router::new().route("/upload", post(upload_bundle))
+    Router::new()
+        .route("/upload", post(upload_bundle))
+        .route("/events", get(stream_events))
+        .route("/devices", get(list_devices))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/upgrade", post(upgrade_job))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
+        .route("/queue", get(get_queue))
+        .route("/artifacts", get(list_artifacts).post(store_artifacts))
+        .route("/artifacts/:sha256", get(download_artifact))
+        .route("/artifacts/:sha256/metadata", get(artifact_metadata))
+        .route("/artifacts/:sha256/install", post(install_artifact))
 }
 
-/// Handles the upload of a given bundle and starts the installation process
-async fn upload_bundle(mut multipart: Multipart) -> Result<Response, StatusCode> {
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let filename = match field.file_name() {
-            Some(name) => name.to_string(),
-            None => {
-                error!("Missing filename for the uploaded file");
-                continue;
-            }
-        };
+#[derive(Serialize)]
+struct UploadResponse {
+    job_id: String,
+    /// Artifacts that are going to be installed by the job
+    artifacts: Vec<Artifact>,
+}
 
-        let bytes = match field.bytes().await {
-            Ok(data) => data,
-            Err(err) => {
-                error!("Failed to get bytes from multipart: {}", err.to_string());
-                continue;
-            }
-        };
+/// Device selectors that can be given as query parameters to `/upload`. The same keys are accepted
+/// as multipart text fields, see [DeviceFilter::set_selector]
+#[derive(Deserialize)]
+struct UploadQuery {
+    device_id: Option<String>,
+    device_name: Option<String>,
+    emulator: Option<String>,
+    os_version: Option<String>,
+    /// Jobs with higher priority go ahead in the install queue
+    priority: Option<i32>,
+    /// What to do when the app is already installed, [InstallMode::CleanInstall] by default
+    install_mode: Option<InstallMode>,
+    /// Stored in the metadata of the uploaded artifacts
+    uploader: Option<String>,
+}
 
-        let download_dir = &ENV_DATA.lock().unwrap().download_default_dir;
-        if !Path::new(&download_dir).exists() {
-            match std::fs::create_dir_all(&download_dir) {
-                Ok(_) => info!("Created download directory {}", &download_dir),
-                Err(err) => {
-                    error!(
-                        "Failed to create download dir {}:\n{}",
-                        &download_dir,
-                        err.to_string()
-                    );
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                }
+impl UploadQuery {
+    fn to_filter(&self) -> Result<DeviceFilter, String> {
+        device_filter([
+            ("device_id", &self.device_id),
+            ("device_name", &self.device_name),
+            ("emulator", &self.emulator),
+            ("os_version", &self.os_version),
+        ])
+    }
+}
+
+/// Builds the [DeviceFilter] from the selectors given as query parameters
+fn device_filter(selectors: [(&str, &Option<String>); 4]) -> Result<DeviceFilter, String> {
+    let mut filter = DeviceFilter::default();
+    for (key, value) in selectors {
+        if let Some(value) = value {
+            filter.set_selector(key, value)?;
+        }
+    }
+    Ok(filter)
+}
+
+#[derive(Deserialize)]
+struct DevicesQuery {
+    /// Either `android` or `ios`
+    os: Option<String>,
+    emulator: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct DeviceInfo {
+    #[serde(flatten)]
+    device: Device,
+    status: DeviceStatus,
+}
+
+/// Returns all the devices currently connected to the hub along with their status
+async fn list_devices(
+    Query(query): Query<DevicesQuery>,
+) -> Result<Json<Vec<DeviceInfo>>, StatusCode> {
+    let os_type = match query.os {
+        Some(os) => match OsType::from_str(&os) {
+            Ok(os_type) => Some(os_type),
+            Err(_) => {
+                error!("Invalid os type: {}", &os);
+                return Err(StatusCode::BAD_REQUEST);
             }
+        },
+        None => None,
+    };
+    let filter = DeviceFilter {
+        os_type,
+        emulator: query.emulator,
+        ..Default::default()
+    };
+
+    let devices = tokio::task::spawn_blocking(move || {
+        find_devices(&filter).map(|adapters| {
+            adapters
+                .iter()
+                .map(|adapter| DeviceInfo {
+                    device: adapter.get_device().clone(),
+                    status: adapter.get_device_status(),
+                })
+                .collect::<Vec<DeviceInfo>>()
+        })
+    })
+    .await
+    .map_err(|err| {
+        error!("Failed to list devices: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    devices.map(Json).map_err(|err| {
+        error!("Failed to find devices: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// When set, only the events of the given job are streamed
+    job_id: Option<String>,
+}
+
+/// Streams the installation events as Server-Sent Events
+async fn stream_events(
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = BroadcastStream::new(events::subscribe()).filter_map(move |event| {
+        // Lagged receivers just skip the events they missed
+        let event = event.ok()?;
+        if query.job_id.as_ref().is_some_and(|id| id != &event.job_id) {
+            return None;
         }
+        Event::default()
+            .event("install")
+            .json_data(&event)
+            .ok()
+            .map(Ok)
+    });
 
-        let temp_file_path = format!("{}/{}", &download_dir, filename);
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
 
-        let temp_file = Path::new(&temp_file_path);
+#[derive(Deserialize)]
+struct JobsQuery {
+    /// Id of a targeted device or part of its name, ignoring the case
+    device: Option<String>,
+    /// Unix timestamp in milliseconds, only the jobs still running at that time are returned
+    since: Option<u64>,
+    /// Unix timestamp in milliseconds, only the jobs created before it are returned
+    until: Option<u64>,
+    status: Option<JobStatus>,
+}
 
-        let extension = match temp_file.extension() {
-            Some(extension) => {
-                let ext = extension.to_str().unwrap().to_string();
-                if ext != "zip" {
-                    error!("Only zip files are supported. Received {}", &ext);
-                    return Err(StatusCode::BAD_REQUEST);
+/// Returns the jobs stored in the history, newest first. The logs are left out, they can be read
+/// through `/jobs/{id}`
+async fn list_jobs(Query(query): Query<JobsQuery>) -> Json<Vec<Job>> {
+    let jobs = registry::find_jobs(|job| {
+        query
+            .device
+            .as_ref()
+            .is_none_or(|device| job.targets_device(device))
+            && query
+                .since
+                .is_none_or(|since| job.finished_at.is_none_or(|finished| finished >= since))
+            && query.until.is_none_or(|until| job.created_at <= until)
+            && query.status.is_none_or(|status| job.status == status)
+    });
+
+    Json(
+        jobs.into_iter()
+            .map(|mut job| {
+                job.log.clear();
+                job
+            })
+            .collect(),
+    )
+}
+
+/// Returns the current status of the job with the given id
+async fn get_job(UrlPath(job_id): UrlPath<String>) -> Result<Json<Job>, StatusCode> {
+    registry::get_job(&job_id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Cancels the job with the given id: the devices that didn't start yet are skipped and the
+/// commands running on the other ones are killed.
+///
+/// Returns [StatusCode::CONFLICT] if the job is already finished
+async fn cancel_job(UrlPath(job_id): UrlPath<String>) -> Result<Json<Job>, StatusCode> {
+    match registry::cancel_job(&job_id) {
+        Ok(job) => {
+            info!("Cancelled job {}", &job_id);
+            // Lets the queued installs of the job return without waiting for their device
+            queue::dispatch();
+            Ok(Json(job))
+        }
+        Err(CancelError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(CancelError::NotRunning) => {
+            error!("Job {} is not running, cannot cancel it", &job_id);
+            Err(StatusCode::CONFLICT)
+        }
+    }
+}
+
+/// Returns the installs currently running and the ones waiting for their device
+async fn get_queue() -> Json<QueueSnapshot> {
+    Json(queue::snapshot())
+}
+
+/// Handles the upload of a given bundle and starts the installation process.
+///
+/// Every uploaded file is saved in the artifact store, unless an identical one is already there.
+/// The target devices can be restricted through device selectors, given either as query parameters
+/// or as multipart text fields.
+///
+/// Returns the id of the job that can be used to follow the installation through `/jobs/{id}`
+async fn upload_bundle(
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    let mut temp_dirs = Vec::<String>::new();
+    // Any failure below leaves the extracted uploads behind, they are removed before returning
+    let upload = async {
+        let mut bundles = Vec::<String>::new();
+        let mut artifacts = Vec::<Artifact>::new();
+        let mut priority = query.priority.unwrap_or_default();
+        let mut install_mode = query.install_mode.unwrap_or_default();
+        let mut uploader = query.uploader.clone();
+        let mut filter = query.to_filter().map_err(|err| {
+            error!("{}", err);
+            StatusCode::BAD_REQUEST
+        })?;
+
+        while let Some(field) = multipart.next_field().await.map_err(|err| {
+            error!("Failed to read multipart field: {}", err);
+            err.status()
+        })? {
+            let filename = match field.file_name() {
+                Some(name) => name.to_string(),
+                None => {
+                    let key = field.name().unwrap_or_default().to_string();
+                    let value = field.text().await.map_err(|err| {
+                        error!("Failed to read field {}: {}", &key, err);
+                        StatusCode::BAD_REQUEST
+                    })?;
+                    if key == "priority" {
+                        priority = value.parse::<i32>().map_err(|err| {
+                            error!("Invalid priority {}: {}", &value, err);
+                            StatusCode::BAD_REQUEST
+                        })?;
+                        continue;
+                    }
+                    if key == "install_mode" {
+                        install_mode = InstallMode::from_str(&value).map_err(|err| {
+                            error!("Invalid install mode {}: {}", &value, err);
+                            StatusCode::BAD_REQUEST
+                        })?;
+                        continue;
+                    }
+                    if key == "uploader" {
+                        uploader = Some(value);
+                        continue;
+                    }
+                    match filter.set_selector(&key, &value) {
+                        Ok(true) => {}
+                        Ok(false) => error!("Unknown field {}, ignoring it", &key),
+                        Err(err) => {
+                            error!("{}", err);
+                            return Err(StatusCode::BAD_REQUEST);
+                        }
+                    }
+                    continue;
                 }
-                ext
-            }
-            None => {
-                error!("Missing extension in file {}", &filename);
-                return Err(StatusCode::UNPROCESSABLE_ENTITY);
-            }
-        };
+            };
 
-        if temp_file.exists() {
-            match std::fs::remove_file(&temp_file_path) {
-                Ok(_) => info!("Already existent file {} has been removed", &filename),
-                Err(err) => error!("Couldn't remove file {}:\n{}", &filename, err.to_string()),
-            }
+            let artifact = receive_artifact(field, filename, uploader.clone()).await?;
+            let download_dir = String::from(&ENV_DATA.lock().unwrap().download_default_dir);
+            let (paths, temp_dir) = prepare_bundles(&artifact, &download_dir).await?;
+            bundles.extend(paths);
+            temp_dirs.extend(temp_dir);
+            artifacts.push(artifact);
         }
 
-        let extraction_folder = temp_file
-            .parent()
-            .unwrap()
-            .join(filename.replace(&format!(".{}", extension), ""))
-            .join("extract");
+        if bundles.is_empty() {
+            error!("No bundles found in the uploaded files");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok((bundles, artifacts, filter, priority, install_mode))
+    };
+    let (bundles, artifacts, filter, priority, install_mode) = match upload.await {
+        Ok(upload) => upload,
+        Err(status) => {
+            remove_temp_dirs(&temp_dirs);
+            return Err(status);
+        }
+    };
 
-        info!(
-            "Extracting zip in folder: {}",
-            &extraction_folder.to_str().unwrap().to_string()
+    let job_id = start_job(bundles, temp_dirs, filter, priority, install_mode);
+    enforce_retention(&artifacts);
+    Ok(Json(UploadResponse { job_id, artifacts }))
+}
+
+/// Saves the uploaded file in the artifact store, see [artifact_store::store]
+async fn receive_artifact(
+    field: Field<'_>,
+    filename: String,
+    uploader: Option<String>,
+) -> Result<Artifact, StatusCode> {
+    let Some(filename) = artifact_store::sanitize_file_name(&filename) else {
+        error!("Invalid file name {}", &filename);
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if BundleKind::from_file_name(&filename).is_none() {
+        error!(
+            "Unsupported file {}. Expected an aab, apk, apks, ipa, app.zip or zip file",
+            &filename
         );
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-        match create_dir_all(&extraction_folder) {
-            Ok(_) => info!("Created all directories to the extraction path"),
+    let (download_dir, max_upload_size) = {
+        let env = ENV_DATA.lock().unwrap();
+        (String::from(&env.download_default_dir), env.max_upload_size)
+    };
+    if !Path::new(&download_dir).exists() {
+        match std::fs::create_dir_all(&download_dir) {
+            Ok(_) => info!("Created download directory {}", &download_dir),
             Err(err) => {
                 error!(
-                    "Failed to create directories in path to extraction folder:\n{}",
+                    "Failed to create download dir {}:\n{}",
+                    &download_dir,
                     err.to_string()
                 );
                 return Err(StatusCode::INTERNAL_SERVER_ERROR);
             }
         }
+    }
 
-        match zip_extract::extract(Cursor::new(&bytes), &extraction_folder, true) {
-            Ok(_) => info!(
-                "Extracted zip file in {}",
-                &extraction_folder.to_str().unwrap()
-            ),
-            Err(err) => {
-                error!("Failed to extract zip file:\n{}", err.to_string());
-                return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    // Uploads are written under a unique name, so that concurrent uploads of files with the
+    // same name don't overwrite each other
+    let temp_file_path = format!("{}/{}.part", &download_dir, Uuid::new_v4());
+    let (size, sha256) = save_field(field, &temp_file_path, max_upload_size).await?;
+    info!("Saved {} ({} bytes, sha256 {})", &filename, size, &sha256);
+
+    let (artifact, _) = tokio::task::spawn_blocking(move || {
+        artifact_store::store(
+            Path::new(&temp_file_path),
+            &sha256,
+            &filename,
+            size,
+            uploader,
+        )
+    })
+    .await
+    .map_err(|err| err.to_string())
+    .and_then(|result| result)
+    .map_err(|err| {
+        error!("Failed to store the artifact: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(artifact)
+}
+
+/// Returns the bundles to install for the given artifact. Zipped artifacts are extracted in a new
+/// directory of `download_dir`, returned along with the bundles so that it can be removed once the
+/// job is done
+async fn prepare_bundles(
+    artifact: &Artifact,
+    download_dir: &str,
+) -> Result<(Vec<String>, Option<String>), StatusCode> {
+    let filename = &artifact.file_name;
+    let artifact_path = artifact.path().to_str().unwrap().to_string();
+    let kind = match BundleKind::from_file_name(filename) {
+        Some(kind) => kind,
+        None => {
+            error!("Unsupported artifact {}", filename);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if kind.is_installable() {
+        return Ok((vec![artifact_path], None));
+    }
+
+    // The name comes from the client, only its last component is used in the extraction path
+    let stem = artifact_store::sanitize_file_name(
+        &filename[..filename.len() - kind.extension().len() - 1],
+    )
+    .unwrap_or(String::from("bundle"));
+    let upload_folder = Path::new(download_dir).join(Uuid::new_v4().to_string());
+    let temp_dir = Some(upload_folder.to_str().unwrap().to_string());
+    let mut extraction_folder = upload_folder.join("extract");
+    match kind {
+        // The content is extracted in a `.app` directory, which is what idb expects
+        BundleKind::ZippedApp => {
+            extraction_folder = extraction_folder.join(format!("{}.app", stem))
+        }
+        BundleKind::ZippedSplitApks => {
+            extraction_folder = extraction_folder.join(format!("{}.splits", stem))
+        }
+        _ => {}
+    }
+
+    info!(
+        "Extracting zip in folder: {}",
+        &extraction_folder.to_str().unwrap().to_string()
+    );
+
+    match create_dir_all(&extraction_folder) {
+        Ok(_) => info!("Created all directories to the extraction path"),
+        Err(err) => {
+            error!(
+                "Failed to create directories in path to extraction folder:\n{}",
+                err.to_string()
+            );
+            remove_temp_dirs(temp_dir.as_slice());
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    let zip_folder = extraction_folder.clone();
+    let extraction = tokio::task::spawn_blocking(move || {
+        let file = File::open(&artifact_path).map_err(|err| err.to_string())?;
+        zip_extract::extract(BufReader::new(file), &zip_folder, true).map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())
+    .and_then(|result| result);
+
+    match extraction {
+        Ok(_) => info!(
+            "Extracted zip file in {}",
+            &extraction_folder.to_str().unwrap()
+        ),
+        Err(err) => {
+            error!("Failed to extract zip file:\n{}", err);
+            remove_temp_dirs(temp_dir.as_slice());
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    if kind != BundleKind::Archive {
+        return Ok((
+            vec![extraction_folder.to_str().unwrap().to_string()],
+            temp_dir,
+        ));
+    }
+
+    let entries = match read_dir(&extraction_folder) {
+        Ok(e) => e.flatten().collect::<Vec<DirEntry>>(),
+        Err(err) => {
+            error!("Failed to read extraction directory: {}", err.to_string());
+            remove_temp_dirs(temp_dir.as_slice());
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    Ok((
+        entries
+            .iter()
+            .map(|bundle_file| bundle_file.path().to_str().unwrap().to_string())
+            .collect(),
+        temp_dir,
+    ))
+}
+
+/// Creates the job and starts installing each bundle in its own thread. The `temp_dirs` are
+/// removed together with the workspace of the job once it's done
+fn start_job(
+    bundles: Vec<String>,
+    temp_dirs: Vec<String>,
+    filter: DeviceFilter,
+    priority: i32,
+    install_mode: InstallMode,
+) -> String {
+    let job_id = registry::create_job(bundles.clone(), priority, install_mode);
+    registry::update_job(&job_id, |job| job.temp_dirs = temp_dirs);
+    info!("Created job {} for {} bundles", &job_id, bundles.len());
+
+    for path in bundles {
+        let temp_job_id = String::from(&job_id);
+        let temp_filter = filter.clone();
+        thread::spawn(move || {
+            match install_bundle_all(&path, &temp_job_id, &temp_filter) {
+                Ok(_) => info!("Installed bundle againts all devices"),
+                Err(err) => {
+                    error!("Failed to install bundle:\n{}", err);
+                    registry::update_job(&temp_job_id, |job| job.errors.push(err));
+                }
+            }
+            complete_artifact(&temp_job_id);
+        });
+    }
+
+    job_id
+}
+
+/// Applies the retention policies of the artifact store in the background, once the request is
+/// done (ex. its job is created). The given artifacts are kept even if they exceed the limits
+fn enforce_retention(artifacts: &[Artifact]) {
+    let keep = artifacts
+        .iter()
+        .map(|artifact| String::from(&artifact.sha256))
+        .collect::<Vec<String>>();
+    tokio::task::spawn_blocking(move || artifact_store::enforce_retention(&keep));
+}
+
+/// Removes the extraction directories of a request that failed before creating its job
+fn remove_temp_dirs(dirs: &[String]) {
+    for dir in dirs {
+        if let Err(err) = remove_dir_all(dir) {
+            error!("Failed to remove directory {}: {}", dir, err);
+        }
+    }
+}
+
+/// Marks one of the artifacts of the job as processed, removing the directories of the job once
+/// it's done
+fn complete_artifact(job_id: &str) {
+    for dir in registry::complete_artifact(job_id) {
+        apks_cache::remove_extracted(&dir);
+        if !Path::new(&dir).exists() {
+            continue;
+        }
+        match remove_dir_all(&dir) {
+            Ok(_) => info!("Removed directory {} of finished job", &dir),
+            Err(err) => error!("Failed to remove directory {}: {}", &dir, err),
+        }
+    }
+}
+
+/// Seconds during which the upgraded app has to keep running when `alive_seconds` is not given
+const DEFAULT_ALIVE_SECONDS: u64 = 10;
+
+/// Parameters of `/jobs/upgrade`, accepts the same selectors and priority of `/upload`
+#[derive(Deserialize)]
+struct UpgradeQuery {
+    /// SHA-256 of the stored artifact of the previous version
+    from: String,
+    /// SHA-256 of the stored artifact of the new version
+    to: String,
+    /// Name of the setup flow run against the previous version, see [UpgradePlan::setup]
+    setup: Option<String>,
+    alive_seconds: Option<u64>,
+    device_id: Option<String>,
+    device_name: Option<String>,
+    emulator: Option<String>,
+    os_version: Option<String>,
+    priority: Option<i32>,
+}
+
+/// Creates a job testing the upgrade between two stored artifacts, see [UpgradePlan]. Each
+/// artifact has to contain a single bundle
+async fn upgrade_job(
+    Query(query): Query<UpgradeQuery>,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    let from = artifact_store::get(&query.from).ok_or(StatusCode::NOT_FOUND)?;
+    let to = artifact_store::get(&query.to).ok_or(StatusCode::NOT_FOUND)?;
+    let filter = device_filter([
+        ("device_id", &query.device_id),
+        ("device_name", &query.device_name),
+        ("emulator", &query.emulator),
+        ("os_version", &query.os_version),
+    ])
+    .map_err(|err| {
+        error!("{}", err);
+        StatusCode::BAD_REQUEST
+    })?;
+    if let Some(setup) = &query.setup {
+        setup_flow_path(setup).map_err(|err| {
+            error!("{}", err);
+            StatusCode::BAD_REQUEST
+        })?;
+    }
+
+    let download_dir = String::from(&ENV_DATA.lock().unwrap().download_default_dir);
+    let mut bundles = Vec::<String>::new();
+    let mut temp_dirs = Vec::<String>::new();
+    for artifact in [&from, &to] {
+        let (mut artifact_bundles, temp_dir) = match prepare_bundles(artifact, &download_dir).await
+        {
+            Ok(prepared) => prepared,
+            Err(status) => {
+                remove_temp_dirs(&temp_dirs);
+                return Err(status);
             }
+        };
+        temp_dirs.extend(temp_dir);
+        if artifact_bundles.len() != 1 {
+            error!(
+                "Artifact {} contains {} bundles, an upgrade needs exactly one",
+                &artifact.sha256,
+                artifact_bundles.len()
+            );
+            remove_temp_dirs(&temp_dirs);
+            return Err(StatusCode::BAD_REQUEST);
         }
+        bundles.append(&mut artifact_bundles);
+    }
+    artifact_store::touch(&from.sha256);
+    artifact_store::touch(&to.sha256);
+
+    let to_bundle = bundles.pop().unwrap_or_default();
+    let plan = UpgradePlan {
+        from: bundles.pop().unwrap_or_default(),
+        setup: query.setup,
+        alive_seconds: query.alive_seconds.unwrap_or(DEFAULT_ALIVE_SECONDS),
+    };
+    let job_id = registry::create_job(
+        vec![String::from(&to_bundle)],
+        query.priority.unwrap_or_default(),
+        InstallMode::UpdateInPlace,
+    );
+    registry::update_job(&job_id, |job| {
+        job.temp_dirs = temp_dirs;
+        job.upgrade = Some(plan.clone());
+    });
+    info!(
+        "Created job {} upgrading {} to {}",
+        &job_id, &plan.from, &to_bundle
+    );
 
-        let entries = match read_dir(&extraction_folder) {
-            Ok(e) => e
-                .filter(|item| item.is_ok())
-                .map(|item| item.unwrap())
-                .collect::<Vec<DirEntry>>(),
+    let temp_job_id = String::from(&job_id);
+    thread::spawn(move || {
+        match upgrade_bundle_all(plan, &to_bundle, &temp_job_id, &filter) {
+            Ok(_) => info!("Upgraded bundle on all devices"),
             Err(err) => {
-                error!("Failed to read extraction directory: {}", err.to_string());
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                error!("Failed to upgrade bundle:\n{}", err);
+                registry::update_job(&temp_job_id, |job| job.errors.push(err));
+            }
+        }
+        complete_artifact(&temp_job_id);
+    });
+
+    let artifacts = vec![from, to];
+    enforce_retention(&artifacts);
+    Ok(Json(UploadResponse { job_id, artifacts }))
+}
+
+/// Returns the artifacts in the store, the most recently uploaded first
+async fn list_artifacts() -> Json<Vec<Artifact>> {
+    Json(artifact_store::list())
+}
+
+#[derive(Deserialize)]
+struct StoreQuery {
+    uploader: Option<String>,
+}
+
+/// Saves the uploaded files in the artifact store without installing them, so that they can be
+/// inspected through `/artifacts/{sha256}/metadata` first
+async fn store_artifacts(
+    Query(query): Query<StoreQuery>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<Artifact>>, StatusCode> {
+    let mut uploader = query.uploader;
+    let mut artifacts = Vec::<Artifact>::new();
+
+    while let Some(field) = multipart.next_field().await.map_err(|err| {
+        error!("Failed to read multipart field: {}", err);
+        err.status()
+    })? {
+        let Some(filename) = field.file_name().map(String::from) else {
+            let key = field.name().unwrap_or_default().to_string();
+            let value = field.text().await.map_err(|err| {
+                error!("Failed to read field {}: {}", &key, err);
+                StatusCode::BAD_REQUEST
+            })?;
+            match key.as_str() {
+                "uploader" => uploader = Some(value),
+                _ => error!("Unknown field {}, ignoring it", &key),
             }
+            continue;
         };
+        artifacts.push(receive_artifact(field, filename, uploader.clone()).await?);
+    }
 
-        for bundle_file in entries {
-            let path = bundle_file.path().to_str().unwrap().to_string();
-            thread::spawn(move || match install_bundle_all(&path) {
-                Ok(_) => info!("Installed bundle againts all devices"),
-                Err(err) => error!("Failed to install bundle:\n{}", err),
+    if artifacts.is_empty() {
+        error!("No files found in the upload");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    enforce_retention(&artifacts);
+    Ok(Json(artifacts))
+}
+
+#[derive(Serialize)]
+struct ArtifactMetadata {
+    #[serde(flatten)]
+    artifact: Artifact,
+    /// One entry for each bundle of the artifact, zip archives can contain several of them
+    bundles: Vec<BundleMetadata>,
+}
+
+/// Returns what the stored artifact with the given SHA-256 is going to install
+async fn artifact_metadata(
+    UrlPath(sha256): UrlPath<String>,
+) -> Result<Json<ArtifactMetadata>, StatusCode> {
+    let artifact = artifact_store::get(&sha256).ok_or(StatusCode::NOT_FOUND)?;
+    let download_dir = String::from(&ENV_DATA.lock().unwrap().download_default_dir);
+    let (bundles, temp_dir) = prepare_bundles(&artifact, &download_dir).await?;
+
+    let workspace = Path::new(&download_dir).join(Uuid::new_v4().to_string());
+    let metadata = tokio::task::spawn_blocking(move || {
+        let metadata = create_dir_all(&workspace)
+            .map_err(|err| format!("Failed to create {}: {}", workspace.display(), err))
+            .and_then(|_| {
+                bundles
+                    .iter()
+                    .map(|bundle| metadata::read_metadata(bundle, &workspace))
+                    .collect::<Result<Vec<BundleMetadata>, String>>()
             });
+
+        for dir in temp_dir.iter().map(Path::new).chain([workspace.as_path()]) {
+            if let Err(err) = remove_dir_all(dir) {
+                error!("Failed to remove directory {}: {}", dir.display(), err);
+            }
+        }
+        metadata
+    })
+    .await
+    .map_err(|err| err.to_string())
+    .and_then(|result| result)
+    .map_err(|err| {
+        error!("Failed to read the metadata of {}: {}", &sha256, err);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+
+    Ok(Json(ArtifactMetadata {
+        artifact,
+        bundles: metadata,
+    }))
+}
+
+/// Downloads the file of the stored artifact with the given SHA-256
+async fn download_artifact(UrlPath(sha256): UrlPath<String>) -> Result<Response, StatusCode> {
+    let artifact = artifact_store::get(&sha256).ok_or(StatusCode::NOT_FOUND)?;
+    let file = fs::File::open(artifact.path()).await.map_err(|err| {
+        error!("Failed to open artifact {}: {}", &sha256, err);
+        StatusCode::NOT_FOUND
+    })?;
+
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            String::from("application/octet-stream"),
+        ),
+        (header::CONTENT_LENGTH, artifact.size.to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}\"",
+                artifact.file_name.replace('"', "")
+            ),
+        ),
+    ];
+    Ok((headers, StreamBody::new(ReaderStream::new(file))).into_response())
+}
+
+/// Installs again a stored artifact, accepting the same selectors, priority and install mode of
+/// `/upload` as query parameters
+async fn install_artifact(
+    UrlPath(sha256): UrlPath<String>,
+    Query(query): Query<UploadQuery>,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    let artifact = artifact_store::get(&sha256).ok_or(StatusCode::NOT_FOUND)?;
+    let filter = query.to_filter().map_err(|err| {
+        error!("{}", err);
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let download_dir = String::from(&ENV_DATA.lock().unwrap().download_default_dir);
+    let (bundles, temp_dir) = prepare_bundles(&artifact, &download_dir).await?;
+    if bundles.is_empty() {
+        error!("No bundles found in artifact {}", &sha256);
+        remove_temp_dirs(temp_dir.as_slice());
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    artifact_store::touch(&sha256);
+
+    let job_id = start_job(
+        bundles,
+        temp_dir.into_iter().collect(),
+        filter,
+        query.priority.unwrap_or_default(),
+        query.install_mode.unwrap_or_default(),
+    );
+    let artifacts = vec![artifact];
+    enforce_retention(&artifacts);
+    Ok(Json(UploadResponse { job_id, artifacts }))
+}
+
+/// Streams the content of the multipart field into the file at `path` without keeping it in
+/// memory. Returns the number of bytes written and their SHA-256.
+///
+/// Fails with [StatusCode::PAYLOAD_TOO_LARGE] if the file is bigger than `max_size`; in case of
+/// errors the partial file is removed.
+async fn save_field(
+    mut field: Field<'_>,
+    path: &str,
+    max_size: u64,
+) -> Result<(u64, String), StatusCode> {
+    let mut file = fs::File::create(path).await.map_err(|err| {
+        error!("Failed to create file {}: {}", path, err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut hasher = Sha256::new();
+    let mut written: u64 = 0;
+    let result = loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => {
+                break file.flush().await.map_err(|err| {
+                    error!("Failed to flush file {}: {}", path, err);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })
+            }
+            Err(err) => {
+                error!("Failed to read the uploaded file: {}", err);
+                break Err(err.status());
+            }
+        };
+
+        written += chunk.len() as u64;
+        if written > max_size {
+            error!(
+                "Uploaded file exceeds the maximum size of {} bytes",
+                max_size
+            );
+            break Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        hasher.update(&chunk);
+        if let Err(err) = file.write_all(&chunk).await {
+            error!("Failed to write file {}: {}", path, err);
+            break Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if result.is_err() {
+        drop(file);
+        if let Err(err) = fs::remove_file(path).await {
+            error!("Failed to remove partial file {}: {}", path, err);
         }
     }
-    return Ok(Response::default());
+
+    result.map(|_| (written, format!("{:x}", hasher.finalize())))
 }
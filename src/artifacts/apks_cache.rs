@@ -0,0 +1,202 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, create_dir_all, read_dir, remove_dir_all, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use log::{error, info};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::utils::env_helper::ENV_DATA;
+
+use super::store;
+
+/// One lock for each (aab, device spec), so that the devices sharing a spec wait for the apks
+/// built by the first one instead of building them again
+static BUILD_LOCKS: Lazy<Mutex<HashMap<String, Arc<Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// SHA-256 of the aabs outside of the store, indexed by their path, so that an aab extracted for a
+/// job is hashed once for all its devices
+static AAB_HASHES: Lazy<Mutex<HashMap<String, Arc<OnceLock<HashResult>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+type HashResult = Result<String, String>;
+
+/// Properties of a device used by bundletool to select the apks to build, written as the
+/// `--device-spec` file
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSpec {
+    pub supported_abis: Vec<String>,
+    pub supported_locales: Vec<String>,
+    pub screen_density: u32,
+    pub sdk_version: u32,
+}
+
+impl DeviceSpec {
+    /// Identifies the devices that get the same apks
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        format!("{:x}", Sha256::digest(json.as_bytes()))[..16].to_string()
+    }
+}
+
+fn cache_dir() -> String {
+    String::from(&ENV_DATA.lock().unwrap().apks_cache_dir)
+}
+
+/// Returns the apk set built from the aab for the device spec, building it with `build` the first
+/// time. `build` is called with the path of the device spec file and the path of the apk set to
+/// create, both inside `workspace`.
+///
+/// The apk sets are moved in `{APKS_CACHE_DIR}/{aab_sha256}/{spec_hash}.apks` once built, so they
+/// are reused by the later jobs installing the same aab, while the interrupted builds are removed
+/// with the workspace. The apks of the aabs extracted from zip archives are removed with their job,
+/// see [remove_extracted]. Returns the apk set and whether it was already built
+pub fn get_or_build<F>(
+    aab_path: &str,
+    spec: &DeviceSpec,
+    workspace: &Path,
+    build: F,
+) -> Result<(PathBuf, bool), String>
+where
+    F: FnOnce(&Path, &Path) -> Result<(), String>,
+{
+    let aab_sha256 = aab_sha256(aab_path)?;
+    let spec_hash = spec.hash();
+    let directory = Path::new(&cache_dir()).join(&aab_sha256);
+    let apks_path = directory.join(format!("{}.apks", &spec_hash));
+
+    let lock = Arc::clone(
+        BUILD_LOCKS
+            .lock()
+            .unwrap()
+            .entry(format!("{}/{}", &aab_sha256, &spec_hash))
+            .or_default(),
+    );
+    let _guard = lock.lock().unwrap();
+    if apks_path.exists() {
+        return Ok((apks_path, true));
+    }
+
+    let spec_path = workspace.join(format!("{}.json", &spec_hash));
+    let spec_json = serde_json::to_string_pretty(spec).map_err(|err| err.to_string())?;
+    fs::write(&spec_path, spec_json)
+        .map_err(|err| format!("Failed to write {}: {}", spec_path.display(), err))?;
+
+    let built_path = workspace.join(format!("{}.apks", &spec_hash));
+    build(&spec_path, &built_path)?;
+
+    create_dir_all(&directory)
+        .map_err(|err| format!("Failed to create {}: {}", directory.display(), err))?;
+    store::move_file(&built_path, &apks_path)?;
+
+    info!("Cached apks of {} in {}", aab_path, apks_path.display());
+    Ok((apks_path, false))
+}
+
+/// The aabs of the store are in a directory named after their SHA-256, the other ones (ex.
+/// extracted from a zip archive) are hashed the first time they are built
+fn aab_sha256(aab_path: &str) -> Result<String, String> {
+    let store = String::from(&ENV_DATA.lock().unwrap().artifact_store_dir);
+    if let Some(sha256) = Path::new(aab_path)
+        .strip_prefix(&store)
+        .ok()
+        .and_then(|path| path.iter().next())
+    {
+        return Ok(sha256.to_string_lossy().to_string());
+    }
+
+    let hash = Arc::clone(
+        AAB_HASHES
+            .lock()
+            .unwrap()
+            .entry(String::from(aab_path))
+            .or_default(),
+    );
+    hash.get_or_init(|| hash_file(aab_path)).clone()
+}
+
+fn hash_file(aab_path: &str) -> Result<String, String> {
+    let file =
+        File::open(aab_path).map_err(|err| format!("Failed to open {}: {}", aab_path, err))?;
+    let mut reader = BufReader::new(file);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .map_err(|err| format!("Failed to read {}: {}", aab_path, err))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Forgets the aabs extracted in the directory, which is being removed with its job. Their apks are
+/// removed too, unless a stored artifact or an aab of another job has the same SHA-256
+pub fn remove_extracted(dir: &str) {
+    let released = {
+        let mut hashes = AAB_HASHES.lock().unwrap();
+        let mut released = HashSet::new();
+        hashes.retain(|path, hash| {
+            if !Path::new(path).starts_with(dir) {
+                return true;
+            }
+            if let Some(Ok(sha256)) = hash.get() {
+                released.insert(String::from(sha256));
+            }
+            false
+        });
+        for hash in hashes.values() {
+            if let Some(Ok(sha256)) = hash.get() {
+                released.remove(sha256);
+            }
+        }
+        released
+    };
+    for sha256 in released {
+        if store::get(&sha256).is_none() {
+            remove(&sha256);
+        }
+    }
+}
+
+/// Removes the apks built from the artifact with the given SHA-256
+pub fn remove(sha256: &str) {
+    let prefix = format!("{}/", sha256);
+    BUILD_LOCKS
+        .lock()
+        .unwrap()
+        .retain(|key, _| !key.starts_with(&prefix));
+
+    let directory = Path::new(&cache_dir()).join(sha256);
+    if !directory.exists() {
+        return;
+    }
+    match remove_dir_all(&directory) {
+        Ok(_) => info!("Removed cached apks of {}", sha256),
+        Err(err) => error!("Failed to remove {}: {}", directory.display(), err),
+    }
+}
+
+/// Removes the apks built from aabs that are no longer in the store, including the ones extracted
+/// from zip archives by previous runs
+pub fn prune(stored: &HashSet<String>) {
+    let Ok(entries) = read_dir(cache_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let sha256 = entry.file_name().to_string_lossy().to_string();
+        if !stored.contains(&sha256) {
+            remove(&sha256);
+        }
+    }
+}
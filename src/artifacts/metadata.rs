@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{
+    device_adapter::i_adapter::OsType,
+    utils::{android_manifest, apks_helper, bundle_kind::BundleKind, ipa_helper},
+};
+
+/// Abis that can be found in the `lib` directory of an apk or in the name of an abi split
+const ABIS: [&str; 4] = ["arm64-v8a", "armeabi-v7a", "x86_64", "x86"];
+
+/// What an artifact is going to install
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct BundleMetadata {
+    /// File name of the bundle, artifacts zipping several bundles have an entry for each of them
+    pub bundle: String,
+    pub os_type: Option<OsType>,
+    /// Package name on android, bundle identifier on iOS
+    pub package_name: String,
+    /// `versionCode` on android, `CFBundleVersion` on iOS
+    pub version_code: Option<String>,
+    /// `versionName` on android, `CFBundleShortVersionString` on iOS
+    pub version_name: Option<String>,
+    /// `minSdkVersion` on android, `MinimumOSVersion` on iOS
+    pub min_sdk: Option<String>,
+    /// `targetSdkVersion` on android, `DTPlatformVersion` on iOS
+    pub target_sdk: Option<String>,
+    /// Native abis on android, `UIRequiredDeviceCapabilities` on iOS
+    pub abis: Vec<String>,
+    /// Permissions requested on android, `*UsageDescription` keys on iOS
+    pub permissions: Vec<String>,
+    /// SHA-256 of the signing certificate, on iOS the one of the provisioning profile
+    pub certificate_sha256: Option<String>,
+}
+
+/// Reads the metadata of the installable bundle at `bundle_path`. `workspace` is used for the
+/// files that have to be extracted
+pub fn read_metadata(bundle_path: &str, workspace: &Path) -> Result<BundleMetadata, String> {
+    let path = Path::new(bundle_path);
+    let mut metadata = match BundleKind::from_path(path) {
+        Some(
+            kind @ (BundleKind::Apk | BundleKind::SplitApks | BundleKind::Apks | BundleKind::Aab),
+        ) => android_metadata(bundle_path, kind, workspace)?,
+        Some(BundleKind::Ipa | BundleKind::App) => ios_metadata(bundle_path)?,
+        _ => return Err(format!("Unsupported bundle {}", bundle_path)),
+    };
+
+    metadata.bundle = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(metadata)
+}
+
+/// Reads the manifest of the bundle, the abis come from the native libraries or from the names of
+/// the abi splits
+fn android_metadata(
+    bundle_path: &str,
+    kind: BundleKind,
+    workspace: &Path,
+) -> Result<BundleMetadata, String> {
+    let manifest = android_manifest::read(bundle_path)?;
+    let mut metadata = BundleMetadata {
+        os_type: Some(OsType::Android),
+        package_name: manifest.package_name,
+        version_code: manifest.version_code,
+        version_name: manifest.version_name,
+        min_sdk: manifest.min_sdk,
+        target_sdk: manifest.target_sdk,
+        permissions: manifest.permissions,
+        ..Default::default()
+    };
+
+    match kind {
+        BundleKind::SplitApks => {
+            let apks = apks_helper::list_split_apks(bundle_path)?;
+            add_abis(&mut metadata.abis, apks.iter().map(String::as_str));
+            metadata.certificate_sha256 = apks_helper::certificate_sha256(&apks[0]);
+        }
+        BundleKind::Apks => {
+            let apks = apks_helper::archive_entries(bundle_path)?;
+            add_abis(&mut metadata.abis, apks.iter().map(String::as_str));
+            // The tools can't read the signature of the apks inside the set
+            let base_apk = apks_helper::extract_base_apk(bundle_path, workspace)?;
+            metadata.certificate_sha256 =
+                apks_helper::certificate_sha256(&base_apk.to_string_lossy());
+        }
+        _ => {
+            // Native libraries are in `lib/{abi}/` for apks and `{module}/lib/{abi}/` for aabs
+            let depth = if kind == BundleKind::Aab { 2 } else { 1 };
+            let entries = apks_helper::archive_entries(bundle_path)?;
+            add_abis(
+                &mut metadata.abis,
+                entries.iter().filter_map(|name| {
+                    let mut components = name.split('/');
+                    (components.nth(depth - 1) == Some("lib"))
+                        .then(|| components.next())
+                        .flatten()
+                }),
+            );
+            metadata.certificate_sha256 = apks_helper::certificate_sha256(bundle_path);
+        }
+    }
+    Ok(metadata)
+}
+
+fn ios_metadata(bundle_path: &str) -> Result<BundleMetadata, String> {
+    let plist = ipa_helper::read_info_plist(bundle_path)?;
+
+    Ok(BundleMetadata {
+        os_type: Some(OsType::Ios),
+        package_name: ipa_helper::bundle_identifier(&plist)?,
+        version_code: ipa_helper::string_value(&plist, "CFBundleVersion"),
+        version_name: ipa_helper::string_value(&plist, "CFBundleShortVersionString"),
+        min_sdk: ipa_helper::string_value(&plist, "MinimumOSVersion"),
+        target_sdk: ipa_helper::string_value(&plist, "DTPlatformVersion"),
+        abis: plist
+            .get("UIRequiredDeviceCapabilities")
+            .and_then(|value| value.as_array())
+            .map(|capabilities| {
+                capabilities
+                    .iter()
+                    .filter_map(|capability| capability.as_string())
+                    .filter(|capability| capability.starts_with("arm"))
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        permissions: plist
+            .keys()
+            .filter(|key| key.ends_with("UsageDescription"))
+            .map(String::from)
+            .collect(),
+        certificate_sha256: ipa_helper::certificate_sha256(bundle_path),
+        ..Default::default()
+    })
+}
+
+/// Adds the abis mentioned in `names` (with either `-` or `_` separators) that are not in `abis`
+fn add_abis<'a, I>(abis: &mut Vec<String>, names: I)
+where
+    I: Iterator<Item = &'a str>,
+{
+    for name in names {
+        let name = name.replace('_', "-");
+        // `x86` is a prefix of `x86-64`, so the longest match is checked first
+        if let Some(abi) = ABIS
+            .iter()
+            .find(|abi| name.contains(&abi.replace('_', "-")))
+        {
+            if !abis.iter().any(|known| known == abi) {
+                abis.push(String::from(*abi));
+            }
+        }
+    }
+}
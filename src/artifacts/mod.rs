@@ -0,0 +1,6 @@
+/// Keeps the apks built from the aabs for each device spec
+pub mod apks_cache;
+/// Reads package, versions, sdks, abis, permissions and certificate of the bundles
+pub mod metadata;
+/// Keeps the uploaded artifacts on disk, indexed by their SHA-256
+pub mod store;
@@ -0,0 +1,265 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, create_dir_all, read_dir, remove_dir_all, rename},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    jobs::{
+        job::{now_millis, JobStatus},
+        registry,
+    },
+    utils::{bundle_kind::BundleKind, env_helper::ENV_DATA},
+};
+
+use super::apks_cache;
+
+/// Name of the file, next to the artifact, containing its [Artifact] metadata
+const METADATA_FILE: &str = "metadata.json";
+
+/// Contains all the stored artifacts, indexed by their SHA-256
+static ARTIFACTS: Lazy<Mutex<HashMap<String, Artifact>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Artifact uploaded to the hub, stored in `{ARTIFACT_STORE_DIR}/{sha256}/artifact.{extension}`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Artifact {
+    pub sha256: String,
+    /// Name of the file when it was first uploaded, without its directories. Only kept as
+    /// metadata, the stored file is named after its [BundleKind]
+    pub file_name: String,
+    pub size: u64,
+    pub uploaded_at: u64,
+    pub uploader: Option<String>,
+    /// Last time the artifact was uploaded again or installed, used by the retention policies
+    pub last_used_at: u64,
+}
+
+impl Artifact {
+    /// Directory containing the artifact and its metadata
+    pub fn directory(&self) -> PathBuf {
+        Path::new(&store_dir()).join(&self.sha256)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        let extension =
+            BundleKind::from_file_name(&self.file_name).map_or("bin", |kind| kind.extension());
+        self.directory().join(format!("artifact.{}", extension))
+    }
+
+    fn save_metadata(&self) -> Result<(), String> {
+        let path = self.directory().join(METADATA_FILE);
+        let content = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        fs::write(&path, content)
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+    }
+}
+
+/// Returns the last component of the uploaded file name, [None] if there is no usable name. The
+/// name comes from the client, so it must never be joined to a path as it is
+pub fn sanitize_file_name(file_name: &str) -> Option<String> {
+    let name = file_name.rsplit(['/', '\\']).next()?.trim();
+    match name {
+        "" | "." | ".." => None,
+        _ => Some(String::from(name)),
+    }
+}
+
+fn store_dir() -> String {
+    String::from(&ENV_DATA.lock().unwrap().artifact_store_dir)
+}
+
+/// Reads the metadata of the artifacts already in the store and applies the retention policies
+pub fn load() -> Result<(), String> {
+    let dir = store_dir();
+    create_dir_all(&dir).map_err(|err| format!("Failed to create {}: {}", &dir, err))?;
+
+    let entries = read_dir(&dir).map_err(|err| format!("Failed to read {}: {}", &dir, err))?;
+    let mut artifacts = ARTIFACTS.lock().unwrap();
+    for entry in entries.flatten() {
+        let metadata_path = entry.path().join(METADATA_FILE);
+        let artifact = fs::read_to_string(&metadata_path)
+            .map_err(|err| err.to_string())
+            .and_then(|content| {
+                serde_json::from_str::<Artifact>(&content).map_err(|err| err.to_string())
+            });
+        match artifact {
+            Ok(artifact) if artifact.path().exists() => {
+                artifacts.insert(String::from(&artifact.sha256), artifact);
+            }
+            Ok(artifact) => warn!("Skipping artifact {}, its file is missing", artifact.sha256),
+            Err(err) => warn!(
+                "Skipping {}, invalid metadata: {}",
+                entry.path().display(),
+                err
+            ),
+        }
+    }
+    info!("Loaded {} artifacts from {}", artifacts.len(), &dir);
+    apks_cache::prune(&artifacts.keys().cloned().collect());
+    drop(artifacts);
+
+    enforce_retention(&[]);
+    Ok(())
+}
+
+/// Moves the uploaded file at `temp_path` into the store. If an artifact with the same SHA-256 is
+/// already stored the file is discarded and the existing artifact is returned instead.
+///
+/// Returns the artifact and whether it was already stored
+pub fn store(
+    temp_path: &Path,
+    sha256: &str,
+    file_name: &str,
+    size: u64,
+    uploader: Option<String>,
+) -> Result<(Artifact, bool), String> {
+    let existing = ARTIFACTS.lock().unwrap().get(sha256).cloned();
+    if let Some(artifact) = existing.filter(|artifact| artifact.path().exists()) {
+        info!(
+            "Artifact {} is already stored as {}",
+            sha256, artifact.file_name
+        );
+        if let Err(err) = fs::remove_file(temp_path) {
+            error!("Failed to remove {}: {}", temp_path.display(), err);
+        }
+        touch(sha256);
+        return Ok((artifact, true));
+    }
+
+    let file_name =
+        sanitize_file_name(file_name).ok_or(format!("Invalid file name: {}", file_name))?;
+    let now = now_millis();
+    let artifact = Artifact {
+        sha256: String::from(sha256),
+        file_name: String::from(&file_name),
+        size,
+        uploaded_at: now,
+        uploader,
+        last_used_at: now,
+    };
+
+    let directory = artifact.directory();
+    create_dir_all(&directory)
+        .map_err(|err| format!("Failed to create {}: {}", directory.display(), err))?;
+    move_file(temp_path, &artifact.path())?;
+    artifact.save_metadata()?;
+
+    ARTIFACTS
+        .lock()
+        .unwrap()
+        .insert(String::from(sha256), artifact.clone());
+    info!("Stored artifact {} as {}", sha256, &file_name);
+    Ok((artifact, false))
+}
+
+/// Renames the file, falling back to a copy if the destination is on another file system
+pub fn move_file(from: &Path, to: &Path) -> Result<(), String> {
+    if rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to).map_err(|err| {
+        format!(
+            "Failed to copy {} to {}: {}",
+            from.display(),
+            to.display(),
+            err
+        )
+    })?;
+    if let Err(err) = fs::remove_file(from) {
+        error!("Failed to remove {}: {}", from.display(), err);
+    }
+    Ok(())
+}
+
+pub fn get(sha256: &str) -> Option<Artifact> {
+    ARTIFACTS.lock().unwrap().get(sha256).cloned()
+}
+
+/// Returns all the stored artifacts, the most recently uploaded first
+pub fn list() -> Vec<Artifact> {
+    let mut artifacts = ARTIFACTS
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<Artifact>>();
+    artifacts.sort_by_key(|artifact| std::cmp::Reverse(artifact.uploaded_at));
+    artifacts
+}
+
+/// Marks the artifact as used right now, so that the retention policies keep it longer
+pub fn touch(sha256: &str) {
+    let mut artifacts = ARTIFACTS.lock().unwrap();
+    if let Some(artifact) = artifacts.get_mut(sha256) {
+        artifact.last_used_at = now_millis();
+        if let Err(err) = artifact.save_metadata() {
+            error!("Failed to update artifact {}: {}", sha256, err);
+        }
+    }
+}
+
+/// Removes the artifacts exceeding the limits of `ARTIFACT_MAX_AGE_DAYS`, `ARTIFACT_MAX_COUNT` and
+/// `ARTIFACT_MAX_TOTAL_SIZE`, least recently used first. The artifacts installed by running jobs,
+/// including the previous version of an upgrade, and the ones in `keep` (ex. the artifacts that
+/// were just uploaded) are never removed
+pub fn enforce_retention(keep: &[String]) {
+    let (max_age, max_count, max_total_size) = {
+        let policy = &ENV_DATA.lock().unwrap().artifact_retention;
+        (policy.max_age, policy.max_count, policy.max_total_size)
+    };
+    if max_age.is_none() && max_count.is_none() && max_total_size.is_none() {
+        return;
+    }
+
+    let store = PathBuf::from(store_dir());
+    let in_use = registry::find_jobs(|job| job.status == JobStatus::Running)
+        .iter()
+        .flat_map(|job| {
+            job.artifacts
+                .iter()
+                .chain(job.upgrade.iter().map(|plan| &plan.from))
+        })
+        .filter_map(|path| Path::new(path).strip_prefix(&store).ok())
+        .filter_map(|path| path.iter().next())
+        .map(|sha| sha.to_string_lossy().to_string())
+        .collect::<HashSet<String>>();
+
+    let mut artifacts = ARTIFACTS.lock().unwrap();
+    let mut candidates = artifacts.values().cloned().collect::<Vec<Artifact>>();
+    candidates.sort_by_key(|artifact| artifact.last_used_at);
+
+    let now = now_millis();
+    let mut count = candidates.len();
+    let mut total_size = candidates.iter().map(|artifact| artifact.size).sum::<u64>();
+    for artifact in candidates {
+        let expired = max_age
+            .is_some_and(|age| now.saturating_sub(artifact.last_used_at) > age.as_millis() as u64);
+        let too_many = max_count.is_some_and(|max| count > max);
+        let too_big = max_total_size.is_some_and(|max| total_size > max);
+        if !expired && !too_many && !too_big {
+            continue;
+        }
+        if in_use.contains(&artifact.sha256) || keep.contains(&artifact.sha256) {
+            continue;
+        }
+
+        match remove_dir_all(artifact.directory()) {
+            Ok(_) => {
+                info!(
+                    "Removed artifact {} ({}) from the store",
+                    artifact.sha256, artifact.file_name
+                );
+                artifacts.remove(&artifact.sha256);
+                apks_cache::remove(&artifact.sha256);
+                count -= 1;
+                total_size -= artifact.size;
+            }
+            Err(err) => error!("Failed to remove artifact {}: {}", artifact.sha256, err),
+        }
+    }
+}
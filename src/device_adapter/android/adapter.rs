@@ -1,31 +1,36 @@
+use super::adb_client::AdbClient;
 use crate::{
-    device_adapter::i_adapter::{Device, DeviceStatus, IAdapter, ScreenRequest},
+    artifacts::apks_cache::{self, DeviceSpec},
+    device_adapter::{
+        i_adapter::{Device, DeviceStatus, IAdapter, InstallMode, InstalledApp, ScreenRequest},
+        install_error::{InstallError, InstallErrorCode, Remediation},
+    },
+    jobs::{context::JobContext, events::InstallStage},
     utils::{
+        android_manifest::{self, AndroidManifest},
         apks_helper,
-        command_executor::{self, exec},
+        bundle_kind::BundleKind,
+        command_executor::OutputStream,
         env_helper::ENV_DATA,
+        retry::{retry, Operation},
     },
 };
-use std::{fs::remove_file, str::FromStr};
+use std::path::{Path, PathBuf};
 
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use regex::Regex;
-use strum_macros::EnumString;
+use uuid::Uuid;
 
 pub struct AdbAdapter {
     pub device: Device,
 }
 
-#[derive(Debug, EnumString)]
-enum Abi {
-    #[strum(serialize = "armeabi-v7a")]
-    ArmeabiV7a,
-    #[strum(serialize = "arm64-v8a")]
-    Arm64V8a,
-    #[strum(serialize = "x86")]
-    X86,
-    #[strum(serialize = "x86_64")]
-    X86_64,
+/// What has to be installed on the device
+enum ApkPayload {
+    /// `.apks` archive generated by bundletool
+    ApkSet(String),
+    /// Single apk or base apk followed by its splits
+    Apks(Vec<String>),
 }
 
 trait FromString: Sized {
@@ -40,7 +45,7 @@ impl FromString for DeviceStatus {
         } else if temp == "Dozing" {
             return DeviceStatus::Dozing;
         }
-        return DeviceStatus::Unknown;
+        DeviceStatus::Unknown
     }
 }
 
@@ -50,110 +55,561 @@ impl FromString for bool {
         if temp == "true" {
             return true;
         }
-        return false;
+        false
     }
 }
 
 impl AdbAdapter {
+    /// Runs the command on the device shell through the adb server
+    fn shell(&self, command: &str) -> Result<String, String> {
+        AdbClient::from_env().shell(&self.device.id, command)
+    }
+
+    /// Same as [AdbAdapter::shell] on behalf of the job: the command stops when the job gets
+    /// cancelled and its output is written in the job log
+    fn job_shell(&self, command: &str, ctx: &JobContext) -> Result<String, String> {
+        debug!("[{}] adb shell {}", self.device.name, command);
+        let result = AdbClient::from_env()
+            .with_cancel(ctx.cancel.clone())
+            .shell(&self.device.id, command);
+        match &result {
+            Ok(output) => ctx.log_output(&self.device, OutputStream::Stdout, output),
+            Err(err) => ctx.log_output(&self.device, OutputStream::Stderr, err),
+        }
+        result
+    }
+
     fn dump_sys_value<T>(&self, key: &String, value_key: &String, default_val: T) -> T
     where
         T: FromString,
     {
-        let result = exec(&format!(
-            "adb -s {} shell dumpsys {} | grep {}",
-            self.device.id, key, value_key
-        ));
+        let result = self.shell(&format!("dumpsys {}", key));
 
         match result {
             Ok(output) => {
                 let regex_value = format!(r"{}=(\w+)", value_key);
                 let re = Regex::new(&regex_value).unwrap();
 
-                return re
-                    .captures(&output)
-                    .expect(&format!("Missing {}", value_key))
-                    .get(1)
-                    .map_or(default_val, |m| T::from_string(m.as_str()));
+                output
+                    .lines()
+                    .filter(|line| line.contains(value_key.as_str()))
+                    .find_map(|line| re.captures(line))
+                    .and_then(|captures| captures.get(1))
+                    .map_or(default_val, |m| T::from_string(m.as_str()))
             }
             Err(err) => {
                 error!(
                     "[{}] Failed to get current screen on: {}",
                     self.device.name, err
                 );
-                return default_val;
+                default_val
             }
-        };
+        }
     }
 
     /// Checks wether the app is already installed or not
-    fn is_app_already_installed(&self, package_name: &String) -> Result<bool, String> {
-        return command_executor::exec(&format!(
-            "adb -s {} shell pm list packages {}",
-            self.device.id, package_name
-        ))
-        .map(|res| !res.is_empty())
-        .map_err(|err| {
+    fn is_app_already_installed(
+        &self,
+        package_name: &String,
+        ctx: &JobContext,
+    ) -> Result<bool, String> {
+        // `pm list packages` filters by substring, so other packages could be listed as well
+        self.job_shell(&format!("pm list packages {}", package_name), ctx)
+            .map(|res| {
+                res.lines()
+                    .any(|line| line.trim() == format!("package:{}", package_name))
+            })
+            .map_err(|err| {
+                format!(
+                    "[{}] Failed to check if app {} is installed: {}",
+                    self.device.name, package_name, err
+                )
+            })
+    }
+
+    /// Reads the properties used by bundletool to select the apks for the device
+    fn device_spec(&self, ctx: &JobContext) -> Result<DeviceSpec, String> {
+        let properties = self.job_shell("getprop", ctx).map_err(|err| {
             format!(
-                "[{}] Failed to check if app {} is installed: {}",
-                self.device.name,
-                package_name,
-                err.to_string()
+                "[{}] Failed to read the device properties: {}",
+                self.device.name, err
             )
-        });
+        })?;
+        // Each line looks like `[ro.build.version.sdk]: [33]`
+        let property = |key: &str| {
+            let prefix = format!("[{}]: [", key);
+            properties
+                .lines()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .map(|value| value.trim_end_matches(']').trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let missing = |name: &str| format!("[{}] Could not read the {}", self.device.name, name);
+
+        let supported_abis = property("ro.product.cpu.abilist")
+            .or(property("ro.product.cpu.abi"))
+            .map(|abis| abis.split(',').map(String::from).collect())
+            .ok_or(missing("abis"))?;
+        let sdk_version = property("ro.build.version.sdk")
+            .and_then(|sdk| sdk.parse::<u32>().ok())
+            .ok_or(missing("sdk version"))?;
+        // Emulators set only the `qemu` property
+        let screen_density = property("ro.sf.lcd_density")
+            .or(property("qemu.sf.lcd_density"))
+            .and_then(|density| density.parse::<u32>().ok())
+            .ok_or(missing("screen density"))?;
+        let supported_locales = vec![property("persist.sys.locale")
+            .or(property("ro.product.locale"))
+            .unwrap_or("en-US".to_string())];
+
+        Ok(DeviceSpec {
+            supported_abis,
+            supported_locales,
+            screen_density,
+            sdk_version,
+        })
     }
 
-    /// Gets the architecture for the device connected
-    fn get_device_architecture(&self) -> Result<Abi, String> {
-        let output = command_executor::exec(&format!(
-            "adb -s {} shell getprop ro.product.cpu.abi",
-            self.device.id
-        ))
-        .map(|val| val.trim().to_owned())
-        .map_err(|err| err.to_string())?;
+    /// Returns the apk set built from the aab for the device. The apks are built once for each
+    /// device spec and kept in the cache, so the devices sharing a spec, in this job or in the
+    /// later ones, reuse them
+    pub fn extract_apk(&self, aab_path: &str, ctx: &JobContext) -> Result<String, String> {
+        let spec = self.device_spec(ctx)?;
+        ctx.info(
+            &self.device,
+            InstallStage::ApkExtraction,
+            &format!(
+                "Device spec {}: abis {}, sdk {}, density {}, locales {}",
+                spec.hash(),
+                spec.supported_abis.join(","),
+                spec.sdk_version,
+                spec.screen_density,
+                spec.supported_locales.join(",")
+            ),
+        );
 
-        return Abi::from_str(&output).map_err(|err| {
-            format!(
-                "[{}] Invalid abi {}: {}",
-                self.device.name,
-                &output,
-                err.to_string()
+        let (keystore_path, keystore_alias, keystore_pass) = {
+            let config = &ENV_DATA.lock().unwrap().android_config;
+            (
+                String::from(&config.keystore_path),
+                String::from(&config.keystore_alias),
+                String::from(&config.keystore_pass),
             )
+        };
+
+        let workspace = ctx.device_workspace(&self.device)?;
+        let result =
+            apks_cache::get_or_build(aab_path, &spec, &workspace, |spec_path, output_path| {
+                ctx.info(
+                    &self.device,
+                    InstallStage::ApkExtraction,
+                    &format!("Building apks into {}", output_path.display()),
+                );
+                ctx.exec(
+                    &self.device,
+                    "bundletool",
+                    &[
+                        "build-apks",
+                        &format!("--bundle={}", aab_path),
+                        &format!("--output={}", output_path.display()),
+                        &format!("--device-spec={}", spec_path.display()),
+                        "--overwrite",
+                        &format!("--ks={}", keystore_path),
+                        &format!("--ks-key-alias={}", keystore_alias),
+                        &format!("--key-pass=pass:{}", keystore_pass),
+                        &format!("--ks-pass=pass:{}", keystore_pass),
+                    ],
+                )
+                .map(|_| ())
+            });
+
+        match result {
+            Ok((path, cached)) => {
+                let path = path.to_string_lossy().to_string();
+                let msg = match cached {
+                    true => format!("Reusing the apks built in {}", &path),
+                    false => format!("Extracted apks in {}", &path),
+                };
+                ctx.info(&self.device, InstallStage::ApkExtraction, &msg);
+                Ok(path)
+            }
+            Err(err) => Err(format!(
+                "[{}] failed to extract apk: {}",
+                self.device.name, err
+            )),
+        }
+    }
+
+    /// Reads the manifest of the bundle, before any apk gets built for the device
+    fn read_manifest(
+        &self,
+        bundle_path: &str,
+        ctx: &JobContext,
+    ) -> Result<AndroidManifest, String> {
+        // The names are given to the device shell, they can't contain anything else than the
+        // characters allowed by android
+        let manifest = android_manifest::read(bundle_path).and_then(|manifest| {
+            let names = [
+                Some(&manifest.package_name),
+                manifest.launcher_activity.as_ref(),
+            ];
+            let invalid = names
+                .into_iter()
+                .flatten()
+                .find(|name| !is_valid_name(name))
+                .cloned();
+            match invalid {
+                Some(name) => Err(format!("Invalid name in the manifest: {}", name)),
+                None => Ok(manifest),
+            }
         });
+        match manifest {
+            Ok(manifest) => {
+                ctx.info(
+                    &self.device,
+                    InstallStage::PackageDetection,
+                    &format!(
+                        "Detected package {} version {}",
+                        &manifest.package_name,
+                        manifest.version_name.as_deref().unwrap_or("unknown")
+                    ),
+                );
+                Ok(manifest)
+            }
+            Err(err) => {
+                ctx.error(
+                    &self.device,
+                    InstallStage::PackageDetection,
+                    &format!("Failed to read the manifest: {}", &err),
+                );
+                Err(err)
+            }
+        }
     }
 
-    /// Extracts the apk for the current device's architecture given the aab file
-    pub fn extract_apk(&self, aab_path: &String) -> Result<String, String> {
-        let arch = self.get_device_architecture()?;
-        info!("[{}] Device has arch {:?}", &self.device.name, &arch);
+    /// Installs the payload, handling the app already installed according to the install mode of
+    /// the job
+    fn install_payload(
+        &self,
+        payload: &ApkPayload,
+        package_name: &String,
+        ctx: &JobContext,
+    ) -> Result<(), InstallError> {
+        self.unlock_device();
+        let mode = ctx.install_mode;
+        if matches!(mode, InstallMode::CleanInstall | InstallMode::SkipIfSame) {
+            self.uninstall_if_installed(package_name, ctx)?;
+        }
 
-        let output_path = format!(
-            "{}/{}.apks",
-            ENV_DATA.lock().unwrap().extract_output_dir,
-            &self.device.id
+        ctx.info(
+            &self.device,
+            InstallStage::Install,
+            &format!("Installing app ({})", mode),
         );
+        let allow_downgrade = mode == InstallMode::Force;
+        let mut result = self
+            .install_over(payload, allow_downgrade, ctx)
+            .map_err(|err| InstallError::from(format!("Failed to install apk: {}", err)));
+        if let Err(err) = &result {
+            if self.remediate(err, package_name, ctx)? {
+                result = self
+                    .install_over(payload, allow_downgrade, ctx)
+                    .map_err(|err| InstallError::from(format!("Failed to install apk: {}", err)));
+            }
+        }
+
+        result
+            .map(|_| ctx.info(&self.device, InstallStage::Install, "Installed apk"))
+            .map_err(|err| {
+                let error = match err.code {
+                    InstallErrorCode::SignatureMismatch => {
+                        InstallError::signature_mismatch(package_name)
+                    }
+                    _ => err,
+                };
+                ctx.error(&self.device, InstallStage::Install, &error.to_string());
+                error
+            })
+    }
+
+    /// Applies the automatic [Remediation] of the install error, returns whether the install has
+    /// to be tried again
+    fn remediate(
+        &self,
+        error: &InstallError,
+        package_name: &String,
+        ctx: &JobContext,
+    ) -> Result<bool, String> {
+        match error.remediation {
+            Some(Remediation::FreeStorageAndRetry) => {
+                ctx.warn(
+                    &self.device,
+                    InstallStage::Install,
+                    "Not enough storage, clearing the caches of the apps before retrying",
+                );
+                // Asking for more space than available clears the caches of every app
+                self.job_shell("pm trim-caches 999G", ctx)?;
+                Ok(true)
+            }
+            // The data of the app are lost, which only the force install mode accepts
+            Some(Remediation::UninstallAndRetry)
+                if ctx.install_mode == InstallMode::Force
+                    && self.is_app_already_installed(package_name, ctx)? =>
+            {
+                ctx.warn(
+                    &self.device,
+                    InstallStage::Install,
+                    &format!("Install rejected, reinstalling the app: {}", error),
+                );
+                self.uninstall_if_installed(package_name, ctx)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Installs the payload, replacing the app if it's already installed
+    fn install_over(
+        &self,
+        payload: &ApkPayload,
+        allow_downgrade: bool,
+        ctx: &JobContext,
+    ) -> Result<String, String> {
+        match payload {
+            ApkPayload::ApkSet(path) => {
+                let apks = format!("--apks={}", path);
+                let mut args = vec!["install-apks", &apks, "--device-id", &self.device.id];
+                if allow_downgrade {
+                    args.push("--allow-downgrade");
+                }
+                ctx.exec(&self.device, "bundletool", &args)
+            }
+            ApkPayload::Apks(apks) if apks.len() == 1 => {
+                self.install_apk(&apks[0], allow_downgrade, ctx)
+            }
+            ApkPayload::Apks(apks) => {
+                let mut args = vec!["-s", &self.device.id, "install-multiple", "-r"];
+                if allow_downgrade {
+                    args.push("-d");
+                }
+                args.extend(apks.iter().map(|apk| apk.as_str()));
+                ctx.exec(&self.device, "adb", &args)
+            }
+        }
+    }
+
+    /// Returns the `versionCode` and `versionName` of the installed package, [None] if it's not
+    /// installed
+    fn installed_version(
+        &self,
+        package_name: &str,
+        ctx: &JobContext,
+    ) -> Result<Option<(String, String)>, String> {
+        let output = self.job_shell(&format!("dumpsys package {}", package_name), ctx)?;
+        // The package details follow `Packages:`, each value looks like `versionCode=42`
+        let Some(details) = output.split("Packages:").nth(1) else {
+            return Ok(None);
+        };
+        let value = |key: &str| {
+            let prefix = format!("{}=", key);
+            details
+                .split_whitespace()
+                .find_map(|part| part.strip_prefix(&prefix))
+                .map(String::from)
+        };
+        Ok(value("versionCode").map(|code| (code, value("versionName").unwrap_or_default())))
+    }
+
+    /// Compares the certificate of the installed package with the one of the payload. The base apk
+    /// of the installed package is pulled in the workspace of the device
+    fn is_same_signature(
+        &self,
+        package_name: &str,
+        payload: &ApkPayload,
+        ctx: &JobContext,
+    ) -> Result<bool, String> {
+        let workspace = ctx.device_workspace(&self.device)?;
+        let local_apk = match payload {
+            ApkPayload::Apks(apks) => PathBuf::from(&apks[0]),
+            ApkPayload::ApkSet(path) => apks_helper::extract_base_apk(path, &workspace)?,
+        };
+
+        // Each line looks like `package:/data/app/.../base.apk`
+        let paths = self.job_shell(&format!("pm path {}", package_name), ctx)?;
+        let remote_apk = paths
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("package:"))
+            .find(|path| path.ends_with("/base.apk"))
+            .ok_or(format!("No base apk found for {}", package_name))?;
+        let installed_apk = workspace.join("installed.apk");
+        ctx.exec(
+            &self.device,
+            "adb",
+            &[
+                "-s",
+                &self.device.id,
+                "pull",
+                remote_apk,
+                &installed_apk.to_string_lossy(),
+            ],
+        )?;
+
+        let installed = apks_helper::certificate_sha256(&installed_apk.to_string_lossy());
+        let artifact = apks_helper::certificate_sha256(&local_apk.to_string_lossy());
+        match (installed, artifact) {
+            (Some(installed), Some(artifact)) => Ok(installed == artifact),
+            _ => Err("Could not read the signing certificates".to_string()),
+        }
+    }
 
-        info!(
-            "[{}] Extracting apks into {}",
-            self.device.name, &output_path
+    /// Whether the package is installed with the `versionCode` and `versionName` of the manifest.
+    /// Any failure is reported as a different version, so that the app gets installed
+    fn is_same_version_installed(&self, manifest: &AndroidManifest, ctx: &JobContext) -> bool {
+        let package_name = &manifest.package_name;
+        let installed = match self.installed_version(package_name, ctx) {
+            Ok(Some(version)) => version,
+            Ok(None) => return false,
+            Err(err) => {
+                ctx.warn(
+                    &self.device,
+                    InstallStage::PackageDetection,
+                    &format!("Failed to read the installed version: {}", err),
+                );
+                return false;
+            }
+        };
+        ctx.info(
+            &self.device,
+            InstallStage::PackageDetection,
+            &format!(
+                "Installed version of {}: {} ({})",
+                package_name, installed.1, installed.0
+            ),
         );
 
-        let config = &ENV_DATA.lock().unwrap().android_config;
+        manifest.version_code.as_deref() == Some(installed.0.as_str())
+            && manifest.version_name.as_deref().unwrap_or_default() == installed.1
+    }
+
+    /// Same as [AdbAdapter::is_same_signature], reporting any failure as a different signature
+    fn is_same_signature_installed(
+        &self,
+        package_name: &str,
+        payload: &ApkPayload,
+        ctx: &JobContext,
+    ) -> bool {
+        match self.is_same_signature(package_name, payload, ctx) {
+            Ok(same) => same,
+            Err(err) => {
+                ctx.warn(
+                    &self.device,
+                    InstallStage::PackageDetection,
+                    &format!("Failed to compare the signatures: {}", err),
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns what has to be installed for the bundle, building the apks of an aab
+    fn build_payload(
+        &self,
+        kind: Option<BundleKind>,
+        bundle_path: &str,
+        ctx: &JobContext,
+    ) -> Result<ApkPayload, String> {
+        match kind {
+            Some(BundleKind::Aab) => {
+                let log = |msg: &str| ctx.warn(&self.device, InstallStage::ApkExtraction, msg);
+                let apks = retry(Operation::ApkBuild, ctx, &log, || {
+                    self.extract_apk(bundle_path, ctx)
+                })?;
+                Ok(ApkPayload::ApkSet(apks))
+            }
+            Some(BundleKind::Apks) => Ok(ApkPayload::ApkSet(String::from(bundle_path))),
+            Some(BundleKind::SplitApks) => {
+                Ok(ApkPayload::Apks(apks_helper::list_split_apks(bundle_path)?))
+            }
+            _ => Ok(ApkPayload::Apks(vec![String::from(bundle_path)])),
+        }
+    }
+
+    /// Pushes the apk in a temporary directory of the device and installs it from there
+    fn install_apk(
+        &self,
+        apk_path: &String,
+        allow_downgrade: bool,
+        ctx: &JobContext,
+    ) -> Result<String, String> {
+        let local_path = Path::new(apk_path);
+        // The name of the uploaded file can't go through the device shell, the apk is pushed under
+        // a generated one
+        let remote_path = format!("/data/local/tmp/dhh-{}.apk", Uuid::new_v4());
+
+        AdbClient::from_env().with_cancel(ctx.cancel.clone()).push(
+            &self.device.id,
+            local_path,
+            &remote_path,
+        )?;
+        let result = self
+            .job_shell(
+                &format!(
+                    "pm install -r {}{}",
+                    if allow_downgrade { "-d " } else { "" },
+                    remote_path
+                ),
+                ctx,
+            )
+            .and_then(|output| match output.contains("Success") {
+                true => Ok(output),
+                false => Err(output.trim().to_string()),
+            });
 
-        return command_executor::exec(&format!(
-            "bundletool build-apks --bundle={} --output={} --connected-device --device-id {} --ks={} --ks-key-alias={} --key-pass=pass:{} --ks-pass=pass:{}",
-            &aab_path, &output_path, self.device.id,config.keystore_path,  config.keystore_alias, config.keystore_pass, config.keystore_pass
-        ))
+        // Not bound to the job, the apk is removed even when the job got cancelled
+        if let Err(err) = self.shell(&format!("rm -f {}", remote_path)) {
+            warn!(
+                "[{}] Failed to remove {}: {}",
+                self.device.name, remote_path, err
+            );
+        }
+        result
+    }
+
+    /// Uninstalls the app with the given package if it's installed on the device
+    fn uninstall_if_installed(
+        &self,
+        package_name: &String,
+        ctx: &JobContext,
+    ) -> Result<(), String> {
+        if !self.is_app_already_installed(package_name, ctx)? {
+            return Ok(());
+        }
+
+        ctx.info(
+            &self.device,
+            InstallStage::Uninstall,
+            &format!(
+                "App {} is already installed. Uninstalling old version..",
+                package_name
+            ),
+        );
+        self.job_shell(&format!("pm uninstall {}", package_name), ctx)
+            .and_then(|output| match output.contains("Success") {
+                true => Ok(()),
+                false => Err(output.trim().to_string()),
+            })
             .map(|_| {
-                info!("[{}] Extracted apks in {}", self.device.name, &output_path);
-                String::from(&output_path)
+                ctx.info(
+                    &self.device,
+                    InstallStage::Uninstall,
+                    "Previous app uninstalled",
+                )
             })
             .map_err(|err| {
-                format!(
-                    "[{}] failed to extract apk: {}",
-                    self.device.name,
-                    err.to_string()
-                )
-            });
+                let msg = format!("[{}] Could not uninstall app: {}", self.device.name, err);
+                ctx.error(&self.device, InstallStage::Uninstall, &msg);
+                msg
+            })
     }
 }
 
@@ -194,106 +650,109 @@ impl IAdapter for AdbAdapter {
         }
     }
 
-    fn open_app(&self, app_name: &String) {
-        let command = exec(&format!(
-            "adb -s {} shell am start -n {}/{}.MainActivity",
-            self.device.id, app_name, app_name
-        ));
+    fn open_app(&self, app: &InstalledApp, ctx: &JobContext) -> Result<(), String> {
+        let app_name = &app.package_name;
+        // Apps without a launcher activity in the manifest are started like the launcher would do
+        let command = match &app.launcher_activity {
+            // Nested classes contain a `$` (ex. `Outer$Inner`), which the device shell would expand
+            Some(activity) => format!("am start -n '{}/{}'", app_name, activity),
+            None => format!(
+                "monkey -p {} -c android.intent.category.LAUNCHER 1",
+                app_name
+            ),
+        };
+        let command = self.job_shell(&command, ctx);
         match command {
-            Ok(_) => info!("[{}] App {} executed", self.device.name, app_name),
-            Err(err) => error!("[{}] Failed to open app: {}", self.device.name, err),
+            Ok(_) => {
+                info!("[{}] App {} executed", self.device.name, app_name);
+                Ok(())
+            }
+            Err(err) => {
+                error!("[{}] Failed to open app: {}", self.device.name, err);
+                Err(format!("Failed to open app {}: {}", app_name, err))
+            }
         }
     }
 
-    fn send_keyevent(&self, key_event: &String) {
-        let command = exec(&format!(
-            "adb -s {} shell input keyevent {}",
-            self.device.id, key_event
-        ));
+    fn is_app_running(&self, app: &InstalledApp, ctx: &JobContext) -> Result<bool, String> {
+        // `pidof` fails when there is no process with the given name
+        self.job_shell(&format!("pidof {} || true", &app.package_name), ctx)
+            .map(|output| !output.trim().is_empty())
+    }
+
+    fn send_keyevent(&self, key_event: &str) {
+        let command = self.shell(&format!("input keyevent {}", key_event));
         match command {
             Ok(_) => info!("[{}] Sent keyevent {}", self.device.name, key_event),
             Err(err) => error!("[{}] Failed to wake device: {}", self.device.name, err),
         }
     }
 
-    fn install_bundle(&self, bundle_path: &String) -> Result<String, String> {
-        if !bundle_path.ends_with(".aab") {
-            error!("Invalid bundle for android device: {}", bundle_path);
-            let msg = format!("Invalid bundle for android device: {}", bundle_path);
-            return Err(msg);
-        }
-
-        let extracted_apks_path = self.extract_apk(bundle_path)?;
-
-        info!(
-            "[{}] Extracted apk at {}",
-            self.device.name, &extracted_apks_path
-        );
-
-        let package_name = match apks_helper::extract_package_name(&extracted_apks_path) {
-            Ok(package) => package,
-            Err(err) => {
-                error!(
-                    "[{}] Failed to extract package: {}",
-                    self.device.name,
-                    err.to_string()
-                );
-                return Err(err.to_string());
+    fn install_bundle(
+        &self,
+        bundle_path: &str,
+        ctx: &JobContext,
+    ) -> Result<InstalledApp, InstallError> {
+        let kind = BundleKind::from_path(Path::new(bundle_path));
+        let manifest = match kind {
+            Some(BundleKind::Aab | BundleKind::Apks | BundleKind::Apk | BundleKind::SplitApks) => {
+                self.read_manifest(bundle_path, ctx)?
+            }
+            _ => {
+                let msg = format!("Invalid bundle for android device: {}", bundle_path);
+                ctx.error(&self.device, InstallStage::Failed, &msg);
+                return Err(InstallError::from(msg));
             }
         };
 
-        self.unlock_device();
-        if self.is_app_already_installed(&package_name.to_string())? {
-            info!(
-                "[{}] App {} is already installed. Uninstalling old version..",
-                self.device.name, &package_name
+        // The versions are compared before building the payload, which is only needed to compare
+        // the signatures of the same version or to install the app
+        let same_version = ctx.install_mode == InstallMode::SkipIfSame
+            && self.is_same_version_installed(&manifest, ctx);
+        let payload = self.build_payload(kind, bundle_path, ctx)?;
+        let skipped =
+            same_version && self.is_same_signature_installed(&manifest.package_name, &payload, ctx);
+        if skipped {
+            ctx.info(
+                &self.device,
+                InstallStage::Install,
+                &format!(
+                    "The same version of {} is already installed, skipping the install",
+                    &manifest.package_name
+                ),
             );
-            let result = command_executor::exec(&format!(
-                "adb -s {} uninstall {}",
-                self.device.id, package_name
-            ))
-            .map(|_| info!("[{}] Previous app uninstalled", self.device.name))
-            .map_err(|err| {
-                format!(
-                    "[{}] Could not uninstall app: {}",
-                    self.device.name,
-                    err.to_string()
-                )
-            });
-            if result.is_err() {
-                return Err(result.unwrap_err());
-            }
+        } else {
+            // Only the install is tried again, the apks are already built
+            let log = |msg: &str| ctx.warn(&self.device, InstallStage::Install, msg);
+            retry(Operation::Install, ctx, &log, || {
+                self.install_payload(&payload, &manifest.package_name, ctx)
+            })?;
         }
 
-        info!("[{}] Installing app", self.device.name);
-        return command_executor::exec(&format!(
-            "bundletool install-apks --apks={} --device-id {}",
-            extracted_apks_path, self.device.id,
-        ))
-        .map(|_| {
-            info!("[{}] Installed apk", self.device.name);
-            match remove_file(&extracted_apks_path) {
-                Ok(_) => info!(
-                    "[{}] Removed file {}",
-                    self.device.name, &extracted_apks_path
-                ),
-                Err(err) => error!(
-                    "[{}] Failed to remove file {}: {}",
-                    self.device.name,
-                    &extracted_apks_path,
-                    err.to_string()
-                ),
-            }
-            package_name
+        Ok(InstalledApp {
+            package_name: manifest.package_name,
+            launcher_activity: manifest.launcher_activity,
+            skipped,
         })
-        .map_err(|err| format!("Failed to install apk: {}", err.to_string()));
     }
 
     fn get_device_name(&self) -> String {
         String::from(&self.device.name)
     }
 
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+
     fn get_os_type(&self) -> crate::device_adapter::i_adapter::OsType {
         self.device.os_type
     }
 }
+
+/// Whether the package or class name only contains the characters allowed by android
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '$'))
+}
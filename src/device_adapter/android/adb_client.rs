@@ -0,0 +1,646 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use tokio_util::sync::CancellationToken;
+
+use crate::utils::{command_executor, env_helper::ENV_DATA};
+
+/// Maximum size of a single `DATA` chunk accepted by the sync protocol
+const SYNC_MAX_CHUNK: usize = 64 * 1024;
+
+/// Permissions used for the files pushed on the device
+const PUSH_FILE_MODE: u32 = 0o100644;
+
+/// How often the blocked reads and writes check the deadline and the cancel token
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Caches whether each device supports the `shell_v2` protocol, which returns the exit code
+static SHELL_V2_SUPPORT: Lazy<Mutex<HashMap<String, bool>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Client for the host protocol spoken by the adb server (by default on `127.0.0.1:5037`).
+///
+/// Every request opens a new connection to the server, just like the `adb` binary does, but
+/// without spawning a new process each time. Like the commands run through
+/// [command_executor::exec_with], each request gives up after `timeout` or as soon as the cancel
+/// token is cancelled
+pub struct AdbClient {
+    address: String,
+    timeout: Duration,
+    cancel: Option<CancellationToken>,
+}
+
+/// Connection to the adb server whose reads and writes fail once its deadline is reached or its
+/// cancel token is cancelled
+struct Connection {
+    stream: TcpStream,
+    timeout: Duration,
+    deadline: Instant,
+    cancel: Option<CancellationToken>,
+}
+
+/// Device as listed by `host:devices-l`
+#[derive(Debug, Clone)]
+pub struct AdbDevice {
+    pub serial: String,
+    /// `device`, `offline`, `unauthorized`, ...
+    pub state: String,
+    /// Properties such as `product`, `model`, `device` and `transport_id`
+    pub properties: HashMap<String, String>,
+}
+
+impl AdbClient {
+    pub fn new(address: &str, timeout: Duration) -> AdbClient {
+        AdbClient {
+            address: String::from(address),
+            timeout,
+            cancel: None,
+        }
+    }
+
+    /// Creates a client for the server configured through `ADB_SERVER_ADDRESS`, whose requests
+    /// time out after `COMMAND_TIMEOUT`
+    pub fn from_env() -> AdbClient {
+        let env_data = ENV_DATA.lock().unwrap();
+        AdbClient::new(&env_data.adb_server_address, env_data.command_timeout)
+    }
+
+    /// Stops the running request as soon as the token is cancelled
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> AdbClient {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Lists all the devices known by the adb server
+    pub fn devices(&self) -> Result<Vec<AdbDevice>, String> {
+        let mut stream = self.connect()?;
+        send_request(&mut stream, "host:devices-l")?;
+        let data = read_length_prefixed(&mut stream)?;
+
+        Ok(data.lines().filter_map(AdbDevice::parse).collect())
+    }
+
+    /// Runs the command on the device shell and returns its stdout.
+    ///
+    /// When the device supports the `shell_v2` protocol a non zero exit code is returned as an
+    /// error containing the stderr, otherwise stdout and stderr are merged together
+    pub fn shell(&self, serial: &str, command: &str) -> Result<String, String> {
+        if self.supports_shell_v2(serial) {
+            return self.shell_v2(serial, command);
+        }
+
+        let mut stream = self.transport(serial)?;
+        send_request(&mut stream, &format!("shell:{}", command))?;
+
+        let mut output = Vec::new();
+        stream
+            .read_to_end(&mut output)
+            .map_err(|err| format!("Failed to read shell output: {}", err))?;
+        Ok(String::from_utf8_lossy(&output).to_string())
+    }
+
+    /// Copies the local file into `remote_path` on the device
+    pub fn push(&self, serial: &str, local_path: &Path, remote_path: &str) -> Result<(), String> {
+        let mut file = File::open(local_path)
+            .map_err(|err| format!("Failed to open {}: {}", local_path.display(), err))?;
+
+        let mut stream = self.transport(serial)?;
+        send_request(&mut stream, "sync:")?;
+
+        let header = format!("{},{}", remote_path, PUSH_FILE_MODE);
+        send_sync_packet(&mut stream, b"SEND", header.as_bytes())?;
+
+        let mut buffer = vec![0u8; SYNC_MAX_CHUNK];
+        loop {
+            let read = file
+                .read(&mut buffer)
+                .map_err(|err| format!("Failed to read {}: {}", local_path.display(), err))?;
+            if read == 0 {
+                break;
+            }
+            send_sync_packet(&mut stream, b"DATA", &buffer[..read])?;
+        }
+
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+        write_all(&mut stream, b"DONE")?;
+        write_all(&mut stream, &mtime.to_le_bytes())?;
+
+        let (id, length) = read_sync_header(&mut stream)?;
+        match &id {
+            b"OKAY" => {
+                _ = send_sync_packet(&mut stream, b"QUIT", &[]);
+                Ok(())
+            }
+            b"FAIL" => Err(format!(
+                "Failed to push {}: {}",
+                local_path.display(),
+                read_string(&mut stream, length)?
+            )),
+            _ => Err(format!(
+                "Unexpected sync response {}",
+                String::from_utf8_lossy(&id)
+            )),
+        }
+    }
+
+    fn shell_v2(&self, serial: &str, command: &str) -> Result<String, String> {
+        let mut stream = self.transport(serial)?;
+        send_request(&mut stream, &format!("shell,v2,raw:{}", command))?;
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut exit_code = None;
+
+        // Each packet is made of 1 byte id, 4 bytes little endian length and the payload
+        let mut header = [0u8; 5];
+        loop {
+            match stream.read_exact(&mut header) {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(format!("Failed to read shell output: {}", err)),
+            }
+            let length = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+            let payload = read_bytes(&mut stream, length as usize)?;
+            match header[0] {
+                1 => stdout.extend(payload),
+                2 => stderr.extend(payload),
+                3 => exit_code = payload.first().copied(),
+                _ => {}
+            }
+        }
+
+        match exit_code {
+            Some(0) | None => Ok(String::from_utf8_lossy(&stdout).to_string()),
+            // Tools such as `pm` print their failures on stdout, it's kept along with stderr
+            Some(code) => Err(format!(
+                "Command `{}` exited with code {}\n{}{}",
+                command,
+                code,
+                String::from_utf8_lossy(&stdout),
+                String::from_utf8_lossy(&stderr)
+            )),
+        }
+    }
+
+    fn supports_shell_v2(&self, serial: &str) -> bool {
+        if let Some(supported) = SHELL_V2_SUPPORT.lock().unwrap().get(serial) {
+            return *supported;
+        }
+
+        let supported = self
+            .connect()
+            .and_then(|mut stream| {
+                send_request(&mut stream, &format!("host-serial:{}:features", serial))?;
+                read_length_prefixed(&mut stream)
+            })
+            .map(|features| features.split(',').any(|f| f == "shell_v2"))
+            .unwrap_or(false);
+
+        SHELL_V2_SUPPORT
+            .lock()
+            .unwrap()
+            .insert(String::from(serial), supported);
+        supported
+    }
+
+    /// Opens a connection bound to the given device
+    fn transport(&self, serial: &str) -> Result<Connection, String> {
+        let mut stream = self.connect()?;
+        send_request(&mut stream, &format!("host:transport:{}", serial))?;
+        Ok(stream)
+    }
+
+    /// Connects to the adb server, starting it if it's not running yet
+    fn connect(&self) -> Result<Connection, String> {
+        let stream = match TcpStream::connect(&self.address) {
+            Ok(stream) => Ok(stream),
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                warn!("adb server is not running, starting it");
+                command_executor::exec("adb", &["start-server"])?;
+                info!("adb server started");
+                TcpStream::connect(&self.address).map_err(|err| {
+                    format!("Failed to connect to adb server {}: {}", self.address, err)
+                })
+            }
+            Err(err) => Err(format!(
+                "Failed to connect to adb server {}: {}",
+                self.address, err
+            )),
+        }?;
+
+        // The reads and writes wake up regularly to check the deadline and the cancel token
+        _ = stream.set_read_timeout(Some(POLL_INTERVAL));
+        _ = stream.set_write_timeout(Some(POLL_INTERVAL));
+        Ok(Connection {
+            stream,
+            timeout: self.timeout,
+            deadline: Instant::now() + self.timeout,
+            cancel: self.cancel.clone(),
+        })
+    }
+}
+
+impl Connection {
+    /// Fails once the request has to stop, with the messages of [command_executor::exec_async] so
+    /// that the errors are classified the same way
+    fn check(&self) -> io::Result<()> {
+        if self
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.is_cancelled())
+        {
+            return Err(io::Error::other("Command cancelled: adb request"));
+        }
+        if Instant::now() >= self.deadline {
+            return Err(io::Error::other(format!(
+                "Command timed out after {}s: adb request",
+                self.timeout.as_secs()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the data like a blocking read, the poll timeouts are not reported
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            self.check()?;
+            match self.stream.read(buf) {
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            self.check()?;
+            match self.stream.write(buf) {
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AdbDevice {
+    /// Parses a line like `emulator-5554 device product:sdk_gphone64 model:Pixel_6 transport_id:1`
+    fn parse(line: &str) -> Option<AdbDevice> {
+        let mut parts = line.split_whitespace();
+        let serial = parts.next()?.to_string();
+        let state = parts.next()?.to_string();
+        let properties = parts
+            .filter_map(|part| part.split_once(':'))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>();
+
+        Some(AdbDevice {
+            serial,
+            state,
+            properties,
+        })
+    }
+}
+
+/// Sends a host request, prefixed by its length as 4 hex digits, and checks the server replied
+/// with `OKAY`
+fn send_request(stream: &mut Connection, request: &str) -> Result<(), String> {
+    write_all(
+        stream,
+        format!("{:04x}{}", request.len(), request).as_bytes(),
+    )?;
+
+    let status = read_bytes(stream, 4)?;
+    match status.as_slice() {
+        b"OKAY" => Ok(()),
+        b"FAIL" => Err(format!(
+            "adb request {} failed: {}",
+            request,
+            read_length_prefixed(stream)?
+        )),
+        _ => Err(format!(
+            "Unexpected response to {}: {}",
+            request,
+            String::from_utf8_lossy(&status)
+        )),
+    }
+}
+
+/// Reads a string prefixed by its length as 4 hex digits
+fn read_length_prefixed(stream: &mut Connection) -> Result<String, String> {
+    let length = read_string(stream, 4)?;
+    let length = usize::from_str_radix(&length, 16)
+        .map_err(|err| format!("Invalid length {}: {}", length, err))?;
+    read_string(stream, length as u32)
+}
+
+fn send_sync_packet(stream: &mut Connection, id: &[u8; 4], data: &[u8]) -> Result<(), String> {
+    write_all(stream, id)?;
+    write_all(stream, &(data.len() as u32).to_le_bytes())?;
+    write_all(stream, data)
+}
+
+fn read_sync_header(stream: &mut Connection) -> Result<([u8; 4], u32), String> {
+    let header = read_bytes(stream, 8)?;
+    let id = [header[0], header[1], header[2], header[3]];
+    let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok((id, length))
+}
+
+fn read_string(stream: &mut Connection, length: u32) -> Result<String, String> {
+    read_bytes(stream, length as usize).map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+}
+
+fn read_bytes(stream: &mut Connection, length: usize) -> Result<Vec<u8>, String> {
+    let mut buffer = vec![0u8; length];
+    stream
+        .read_exact(&mut buffer)
+        .map_err(|err| format!("Failed to read from adb server: {}", err))?;
+    Ok(buffer)
+}
+
+fn write_all(stream: &mut Connection, data: &[u8]) -> Result<(), String> {
+    stream
+        .write_all(data)
+        .map_err(|err| format!("Failed to write to adb server: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use tokio_util::sync::CancellationToken;
+    use uuid::Uuid;
+
+    use crate::device_adapter::install_error::InstallErrorCode;
+
+    use super::AdbClient;
+
+    type Handler = Box<dyn FnOnce(&mut TcpStream) + Send>;
+
+    /// Starts a fake adb server answering its connections, in order, with the given handlers
+    fn serve(handlers: Vec<Handler>) -> (AdbClient, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            for handler in handlers {
+                let (mut stream, _) = listener.accept().unwrap();
+                handler(&mut stream);
+            }
+        });
+        (AdbClient::new(&address, Duration::from_secs(5)), server)
+    }
+
+    /// Reads a host request and replies `OKAY`
+    fn accept_request(stream: &mut TcpStream) -> String {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).unwrap();
+        let length = usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap();
+        let mut request = vec![0u8; length];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(b"OKAY").unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    fn write_length_prefixed(stream: &mut TcpStream, data: &str) {
+        write!(stream, "{:04x}{}", data.len(), data).unwrap();
+    }
+
+    fn read_sync_packet(stream: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).unwrap();
+        let id = String::from_utf8_lossy(&header[..4]).to_string();
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        // `DONE` is followed by the modification time instead of a payload
+        let mut data = vec![0u8; if id == "DONE" { 0 } else { length as usize }];
+        stream.read_exact(&mut data).unwrap();
+        (id, data)
+    }
+
+    /// Answers the `features` request of the device with the given features
+    fn features(features: &'static str) -> Handler {
+        Box::new(move |stream| {
+            assert!(accept_request(stream).ends_with(":features"));
+            write_length_prefixed(stream, features);
+        })
+    }
+
+    /// Answers a `shell,v2` command with the given stdout, stderr and exit code
+    fn shell_v2(
+        command: &'static str,
+        stdout: &'static str,
+        stderr: &'static str,
+        code: u8,
+    ) -> Handler {
+        Box::new(move |stream| {
+            assert!(accept_request(stream).starts_with("host:transport:"));
+            assert_eq!(accept_request(stream), format!("shell,v2,raw:{}", command));
+            for (id, payload) in [
+                (1u8, stdout.as_bytes()),
+                (2, stderr.as_bytes()),
+                (3, &[code]),
+            ] {
+                stream.write_all(&[id]).unwrap();
+                stream
+                    .write_all(&(payload.len() as u32).to_le_bytes())
+                    .unwrap();
+                stream.write_all(payload).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn lists_devices() {
+        let (client, server) = serve(vec![Box::new(|stream| {
+            assert_eq!(accept_request(stream), "host:devices-l");
+            write_length_prefixed(
+                stream,
+                "emulator-5554 device product:sdk_gphone64 model:Pixel_6 transport_id:1\n\
+                 R58M123 unauthorized transport_id:2\n",
+            );
+        })]);
+
+        let devices = client.devices().unwrap();
+        server.join().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].serial, "emulator-5554");
+        assert_eq!(devices[0].state, "device");
+        assert_eq!(devices[0].properties["model"], "Pixel_6");
+        assert_eq!(devices[1].state, "unauthorized");
+    }
+
+    #[test]
+    fn runs_shell_v2_commands() {
+        let (client, server) = serve(vec![
+            features("cmd,shell_v2,stat_v2"),
+            shell_v2("getprop ro.build.version.sdk", "33\n", "", 0),
+            shell_v2("ls /sdcard/missing", "", "No such file or directory\n", 1),
+        ]);
+
+        let output = client.shell("shell-serial", "getprop ro.build.version.sdk");
+        assert_eq!(output, Ok(String::from("33\n")));
+        let err = client
+            .shell("shell-serial", "ls /sdcard/missing")
+            .unwrap_err();
+        server.join().unwrap();
+        assert!(err.contains("exited with code 1"), "{}", err);
+        assert!(err.contains("No such file or directory"), "{}", err);
+    }
+
+    #[test]
+    fn classifies_pm_failures_printed_on_stdout() {
+        let failures = [
+            (
+                "Failure [INSTALL_FAILED_UPDATE_INCOMPATIBLE: Package com.example.app signatures \
+                 do not match previously installed version; ignoring!]\n",
+                InstallErrorCode::SignatureMismatch,
+            ),
+            (
+                "Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]\n",
+                InstallErrorCode::InsufficientStorage,
+            ),
+            (
+                "Failure [INSTALL_FAILED_VERSION_DOWNGRADE: Downgrade detected: Update version \
+                 code 1 is older than current 2]\n",
+                InstallErrorCode::VersionDowngrade,
+            ),
+        ];
+        let (client, server) = serve(
+            [features("shell_v2")]
+                .into_iter()
+                .chain(failures.iter().map(|(stdout, _)| {
+                    shell_v2("pm install -r /data/local/tmp/a.apk", stdout, "", 1)
+                }))
+                .collect(),
+        );
+
+        for (_, code) in failures {
+            let err = client
+                .shell("pm-serial", "pm install -r /data/local/tmp/a.apk")
+                .unwrap_err();
+            assert_eq!(InstallErrorCode::classify(&err), code, "{}", err);
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pushes_files_through_sync() {
+        let content = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let local_path = env::temp_dir().join(format!("dhh-test-{}.apk", Uuid::new_v4()));
+        fs::write(&local_path, &content).unwrap();
+
+        let expected = content.clone();
+        let (client, server) = serve(vec![Box::new(move |stream| {
+            assert!(accept_request(stream).starts_with("host:transport:"));
+            assert_eq!(accept_request(stream), "sync:");
+
+            let (id, header) = read_sync_packet(stream);
+            assert_eq!(id, "SEND");
+            assert_eq!(header, b"/data/local/tmp/dhh.apk,33188");
+            let mut received = Vec::new();
+            loop {
+                match read_sync_packet(stream) {
+                    (id, data) if id == "DATA" => received.extend(data),
+                    (id, _) => {
+                        assert_eq!(id, "DONE");
+                        break;
+                    }
+                }
+            }
+            assert_eq!(received, expected);
+            stream.write_all(b"OKAY\0\0\0\0").unwrap();
+            assert_eq!(read_sync_packet(stream).0, "QUIT");
+        })]);
+
+        let result = client.push("push-serial", &local_path, "/data/local/tmp/dhh.apk");
+        server.join().unwrap();
+        fs::remove_file(&local_path).unwrap();
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn reports_push_failures() {
+        let local_path = env::temp_dir().join(format!("dhh-test-{}.apk", Uuid::new_v4()));
+        fs::write(&local_path, b"apk").unwrap();
+
+        let (client, server) = serve(vec![Box::new(|stream| {
+            accept_request(stream);
+            accept_request(stream);
+            while read_sync_packet(stream).0 != "DONE" {}
+            let message = "couldn't create file: Permission denied";
+            stream.write_all(b"FAIL").unwrap();
+            stream
+                .write_all(&(message.len() as u32).to_le_bytes())
+                .unwrap();
+            stream.write_all(message.as_bytes()).unwrap();
+        })]);
+
+        let result = client.push("push-serial", &local_path, "/system/dhh.apk");
+        server.join().unwrap();
+        fs::remove_file(&local_path).unwrap();
+        assert!(result.unwrap_err().contains("Permission denied"));
+    }
+
+    /// Accepts the command and never answers, until the client gives up
+    fn hanging_shell() -> Handler {
+        Box::new(|stream| {
+            accept_request(stream);
+            accept_request(stream);
+            let mut buffer = [0u8; 16];
+            while stream.read(&mut buffer).is_ok_and(|read| read > 0) {}
+        })
+    }
+
+    #[test]
+    fn stops_requests_on_cancel() {
+        let (client, server) = serve(vec![features(""), hanging_shell()]);
+        let cancel = CancellationToken::new();
+        let client = client.with_cancel(cancel.clone());
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            cancel.cancel();
+        });
+
+        let err = client.shell("cancel-serial", "sleep 60").unwrap_err();
+        server.join().unwrap();
+        assert_eq!(
+            InstallErrorCode::classify(&err),
+            InstallErrorCode::Cancelled
+        );
+    }
+
+    #[test]
+    fn stops_requests_on_timeout() {
+        let (client, server) = serve(vec![features(""), hanging_shell()]);
+        let client = AdbClient::new(&client.address, Duration::from_millis(500));
+
+        let err = client.shell("timeout-serial", "sleep 60").unwrap_err();
+        server.join().unwrap();
+        assert_eq!(InstallErrorCode::classify(&err), InstallErrorCode::Timeout);
+    }
+}
@@ -1,13 +1,18 @@
 use std::fmt::Display;
 
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use strum::Display;
 use strum_macros::EnumString;
 
-use super::{android::adapter::AdbAdapter, ios::adapter::IosAdapter};
+use crate::jobs::context::JobContext;
+
+use super::{android::adapter::AdbAdapter, install_error::InstallError, ios::adapter::IosAdapter};
 
 pub enum ScreenRequest {
     On,
+    // Not requested by the hub yet, but supported by the adapters
+    #[allow(dead_code)]
     Off,
 }
 
@@ -16,24 +21,66 @@ pub trait IAdapter: Sync + Send {
 
     fn get_device_name(&self) -> String;
 
+    fn get_device(&self) -> &Device;
+
     fn toggle_screen(&self, request: &ScreenRequest);
 
     fn unlock_device(&self);
 
-    fn open_app(&self, app_name: &String);
+    fn open_app(&self, app: &InstalledApp, ctx: &JobContext) -> Result<(), String>;
+
+    /// Whether the process of the app is currently running on the device
+    fn is_app_running(&self, app: &InstalledApp, ctx: &JobContext) -> Result<bool, String>;
 
-    fn send_keyevent(&self, key_event: &String);
+    fn send_keyevent(&self, key_event: &str);
 
     fn get_device_status(&self) -> DeviceStatus;
 
-    /// In case of [Ok] returns the name of the bundle installed
-    fn install_bundle(&self, bundle_path: &String) -> Result<String, String>;
+    /// In case of [Ok] returns the app installed
+    fn install_bundle(
+        &self,
+        bundle_path: &str,
+        ctx: &JobContext,
+    ) -> Result<InstalledApp, InstallError>;
+}
+
+/// App installed by [IAdapter::install_bundle]
+#[derive(Debug, Clone)]
+pub struct InstalledApp {
+    /// Package name on android, bundle identifier on iOS
+    pub package_name: String,
+    /// Activity started by the launcher, read from the manifest on android
+    pub launcher_activity: Option<String>,
+    /// The same version was already installed, see [InstallMode::SkipIfSame]
+    pub skipped: bool,
+}
+
+/// How [IAdapter::install_bundle] handles an app that is already installed on the device
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum InstallMode {
+    /// Does nothing if the same version is already installed (`versionCode`, `versionName` and
+    /// signing certificate on android, `CFBundleVersion` and `CFBundleShortVersionString` on
+    /// iOS), otherwise works like [InstallMode::CleanInstall]
+    SkipIfSame,
+    /// Installs over the existing app, keeping its data to test the upgrades. Fails with
+    /// [super::install_error::InstallErrorCode::SignatureMismatch] if the existing app has another signature
+    #[serde(alias = "upgrade")]
+    #[strum(to_string = "update-in-place", serialize = "upgrade")]
+    UpdateInPlace,
+    /// Uninstalls the existing app first, wiping its data
+    #[default]
+    CleanInstall,
+    /// Installs over the existing app even when it's a downgrade. If the install is rejected
+    /// anyway (ex. different signature) the app is uninstalled and installed again
+    Force,
 }
 
 pub fn get_adapter(device: Device) -> Box<dyn IAdapter> {
     match device.os_type {
-        OsType::Android => return Box::new(AdbAdapter { device }),
-        OsType::Ios => return Box::new(IosAdapter { device }),
+        OsType::Android => Box::new(AdbAdapter { device }),
+        OsType::Ios => Box::new(IosAdapter { device }),
         OsType::Invalid => todo!(),
     }
 }
@@ -44,9 +91,76 @@ pub struct Device {
     pub id: String,
     pub os_type: OsType,
     pub emulator: bool,
+    /// Version of the operating system (ex. `13` or `16.4`), empty if unknown
+    pub os_version: String,
+}
+
+/// Restricts the devices returned by [crate::utils::commands::find_devices]. Every [None] field
+/// matches all the devices
+#[derive(Debug, Default, Clone)]
+pub struct DeviceFilter {
+    pub os_type: Option<OsType>,
+    pub emulator: Option<bool>,
+    /// When not empty, only the devices with one of these ids are matched
+    pub ids: Vec<String>,
+    /// Regex that the device name has to match
+    pub name_pattern: Option<Regex>,
+    /// Matches the devices whose os version is equal to this one or is a minor release of it
+    /// (ex. `16` matches both `16` and `16.4`)
+    pub os_version: Option<String>,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, device: &Device) -> bool {
+        self.os_type.is_none_or(|os| device.os_type == os)
+            && self.emulator.is_none_or(|emu| device.emulator == emu)
+            && (self.ids.is_empty() || self.ids.contains(&device.id))
+            && self
+                .name_pattern
+                .as_ref()
+                .is_none_or(|re| re.is_match(&device.name))
+            && self.os_version.as_ref().is_none_or(|version| {
+                device.os_version == *version
+                    || device.os_version.starts_with(&format!("{}.", version))
+            })
+    }
+
+    /// Updates the filter with a selector received from the APIs. Returns `Ok(false)` if the
+    /// given key is not a device selector.
+    ///
+    /// Supported keys are:
+    /// - `device_id`: comma separated list of device ids, can be given multiple times
+    /// - `device_name`: regex that the device name has to match
+    /// - `emulator`: `true` for emulators/simulators only, `false` for physical devices only
+    /// - `os_version`: version of the operating system (ex. `13` or `16.4`)
+    pub fn set_selector(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        let value = value.trim();
+        match key {
+            "device_id" => self.ids.extend(
+                value
+                    .split(',')
+                    .map(|id| id.trim().to_string())
+                    .filter(|id| !id.is_empty()),
+            ),
+            "device_name" => {
+                let re = Regex::new(value)
+                    .map_err(|err| format!("Invalid device_name pattern {}: {}", value, err))?;
+                self.name_pattern = Some(re);
+            }
+            "emulator" => {
+                let emulator = value
+                    .parse::<bool>()
+                    .map_err(|_| format!("Invalid emulator value: {}", value))?;
+                self.emulator = Some(emulator);
+            }
+            "os_version" => self.os_version = Some(value.to_string()),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum DeviceStatus {
     Dozing,
     Awake,
@@ -75,24 +189,34 @@ pub enum OsType {
 
 impl Device {
     pub fn from_parsed(device: &DecodedDevice) -> Device {
-        return Device {
+        Device {
             name: String::from(&device.name),
             id: String::from(&device.id),
             os_type: OsType::from_sdk(&device.sdk),
             emulator: device.emulator,
-        };
+            os_version: version_from_sdk(&device.sdk),
+        }
     }
 }
 
+/// Extracts the os version from the sdk description given by flutter or idb (ex.
+/// `Android 13 (API 33)` or `iOS 16.4 20E247`)
+pub fn version_from_sdk(sdk: &str) -> String {
+    let re = Regex::new(r"(\d+(?:\.\d+)*)").unwrap();
+    re.captures(sdk)
+        .and_then(|captures| captures.get(1))
+        .map_or(String::new(), |m| m.as_str().to_string())
+}
+
 impl OsType {
-    fn from_sdk(sdk: &String) -> OsType {
+    fn from_sdk(sdk: &str) -> OsType {
         match sdk.contains("Android") {
             true => OsType::Android,
             false => {
                 if sdk.contains("iOS") {
                     return OsType::Ios;
                 }
-                return OsType::Invalid;
+                OsType::Invalid
             }
         }
     }
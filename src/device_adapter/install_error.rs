@@ -0,0 +1,345 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Failure of [crate::device_adapter::i_adapter::IAdapter::install_bundle], reported in the job of
+/// the device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstallError {
+    pub code: InstallErrorCode,
+    /// What the hub can do, or the user has to do, to get the install working
+    pub remediation: Option<Remediation>,
+    pub message: String,
+}
+
+/// Cause of an [InstallError], read from the output of adb, bundletool and idb
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallErrorCode {
+    /// `INSTALL_FAILED_INSUFFICIENT_STORAGE`
+    InsufficientStorage,
+    /// `INSTALL_FAILED_UPDATE_INCOMPATIBLE`: the installed app is signed with another certificate,
+    /// so it can't be updated without uninstalling it (and losing its data)
+    SignatureMismatch,
+    /// `INSTALL_FAILED_VERSION_DOWNGRADE`: the installed app has a higher version code
+    VersionDowngrade,
+    /// `INSTALL_FAILED_NO_MATCHING_ABIS`: the native libraries don't support the device cpu
+    NoMatchingAbis,
+    /// `INSTALL_FAILED_OLDER_SDK`: the device runs a version older than the `minSdkVersion`
+    OlderSdk,
+    /// `INSTALL_PARSE_FAILED_*`: the apk is corrupted or not signed
+    InvalidPackage,
+    /// `INSTALL_FAILED_USER_RESTRICTED`: installs over USB are disabled on the device
+    UserRestricted,
+    /// The iOS device rejected the provisioning profile or the signature of the app
+    VerificationFailed,
+    DeviceOffline,
+    /// The device didn't accept the USB debugging prompt
+    Unauthorized,
+    DeviceNotFound,
+    /// The connection with the device or the adb server dropped while the command was running
+    ConnectionLost,
+    /// The command took longer than `COMMAND_TIMEOUT`
+    Timeout,
+    Cancelled,
+    Unknown,
+}
+
+/// Strategy resolving an [InstallErrorCode]. The adapters apply [Remediation::FreeStorageAndRetry]
+/// on their own, [Remediation::UninstallAndRetry] only in the `force` install mode, the other ones
+/// are left to the user
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Remediation {
+    /// Clears the caches of the apps on the device, then installs again
+    FreeStorageAndRetry,
+    /// Uninstalls the app, wiping its data, then installs again. Applied by the `force` install
+    /// mode only
+    UninstallAndRetry,
+    /// Reconnect the device, or restart adb, then retry the job
+    ReconnectDevice,
+    /// Accept the USB debugging prompt on the device, then retry the job
+    AcceptDebuggingPrompt,
+    /// Enable the installs over USB in the developer options of the device
+    AllowUsbInstalls,
+    /// Build the app for the abis and the os version of the device
+    UseCompatibleBuild,
+    /// Sign the app with a certificate or a provisioning profile accepted by the device
+    FixSigning,
+}
+
+/// Markers found in the output of the failed commands, checked in order
+const MARKERS: &[(&str, InstallErrorCode)] = &[
+    ("Command cancelled", InstallErrorCode::Cancelled),
+    ("Job cancelled", InstallErrorCode::Cancelled),
+    ("Command timed out", InstallErrorCode::Timeout),
+    (
+        "INSTALL_FAILED_INSUFFICIENT_STORAGE",
+        InstallErrorCode::InsufficientStorage,
+    ),
+    ("not enough space", InstallErrorCode::InsufficientStorage),
+    (
+        "INSTALL_FAILED_UPDATE_INCOMPATIBLE",
+        InstallErrorCode::SignatureMismatch,
+    ),
+    (
+        "signatures do not match",
+        InstallErrorCode::SignatureMismatch,
+    ),
+    (
+        "MismatchedApplicationIdentifierEntitlement",
+        InstallErrorCode::SignatureMismatch,
+    ),
+    (
+        "INSTALL_FAILED_VERSION_DOWNGRADE",
+        InstallErrorCode::VersionDowngrade,
+    ),
+    ("Downgrade detected", InstallErrorCode::VersionDowngrade),
+    (
+        "INSTALL_FAILED_NO_MATCHING_ABIS",
+        InstallErrorCode::NoMatchingAbis,
+    ),
+    (
+        "INSTALL_FAILED_CPU_ABI_INCOMPATIBLE",
+        InstallErrorCode::NoMatchingAbis,
+    ),
+    ("INSTALL_FAILED_OLDER_SDK", InstallErrorCode::OlderSdk),
+    ("INSTALL_PARSE_FAILED", InstallErrorCode::InvalidPackage),
+    (
+        "INSTALL_FAILED_INVALID_APK",
+        InstallErrorCode::InvalidPackage,
+    ),
+    (
+        "INSTALL_FAILED_USER_RESTRICTED",
+        InstallErrorCode::UserRestricted,
+    ),
+    (
+        "ApplicationVerificationFailed",
+        InstallErrorCode::VerificationFailed,
+    ),
+    ("device offline", InstallErrorCode::DeviceOffline),
+    ("device unauthorized", InstallErrorCode::Unauthorized),
+    ("device not found", InstallErrorCode::DeviceNotFound),
+    ("No devices found", InstallErrorCode::DeviceNotFound),
+    // ideviceinstaller: No device found with udid 00008030-..., is it plugged in?
+    ("No device found", InstallErrorCode::DeviceNotFound),
+    // adb: device 'emulator-5554' not found
+    ("adb: device '", InstallErrorCode::DeviceNotFound),
+    ("protocol fault", InstallErrorCode::ConnectionLost),
+    ("error: closed", InstallErrorCode::ConnectionLost),
+    ("Connection refused", InstallErrorCode::ConnectionLost),
+    ("Connection reset", InstallErrorCode::ConnectionLost),
+    ("Broken pipe", InstallErrorCode::ConnectionLost),
+];
+
+impl InstallErrorCode {
+    /// Finds the cause of the failure in the output of the command
+    pub fn classify(output: &str) -> InstallErrorCode {
+        MARKERS
+            .iter()
+            .find(|(marker, _)| output.contains(marker))
+            .map_or(InstallErrorCode::Unknown, |(_, code)| *code)
+    }
+
+    /// Whether the operation can succeed when tried again as it is, see
+    /// [crate::utils::retry::retry]
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            InstallErrorCode::DeviceOffline
+                | InstallErrorCode::DeviceNotFound
+                | InstallErrorCode::ConnectionLost
+                | InstallErrorCode::Timeout
+        )
+    }
+
+    pub fn remediation(&self) -> Option<Remediation> {
+        match self {
+            InstallErrorCode::InsufficientStorage => Some(Remediation::FreeStorageAndRetry),
+            InstallErrorCode::SignatureMismatch | InstallErrorCode::VersionDowngrade => {
+                Some(Remediation::UninstallAndRetry)
+            }
+            InstallErrorCode::NoMatchingAbis | InstallErrorCode::OlderSdk => {
+                Some(Remediation::UseCompatibleBuild)
+            }
+            InstallErrorCode::InvalidPackage | InstallErrorCode::VerificationFailed => {
+                Some(Remediation::FixSigning)
+            }
+            InstallErrorCode::UserRestricted => Some(Remediation::AllowUsbInstalls),
+            InstallErrorCode::DeviceOffline
+            | InstallErrorCode::DeviceNotFound
+            | InstallErrorCode::ConnectionLost => Some(Remediation::ReconnectDevice),
+            InstallErrorCode::Unauthorized => Some(Remediation::AcceptDebuggingPrompt),
+            InstallErrorCode::Timeout | InstallErrorCode::Cancelled | InstallErrorCode::Unknown => {
+                None
+            }
+        }
+    }
+}
+
+impl InstallError {
+    pub fn new(code: InstallErrorCode, message: String) -> Self {
+        InstallError {
+            code,
+            remediation: code.remediation(),
+            message,
+        }
+    }
+
+    /// The installed app with the given package is signed with another certificate
+    pub fn signature_mismatch(package_name: &str) -> Self {
+        InstallError::new(
+            InstallErrorCode::SignatureMismatch,
+            format!(
+                "The installed {} is signed with a different certificate, it can't be updated in \
+                 place",
+                package_name
+            ),
+        )
+    }
+}
+
+impl Display for InstallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Classifies the message with [InstallErrorCode::classify]
+impl From<String> for InstallError {
+    fn from(message: String) -> Self {
+        InstallError::new(InstallErrorCode::classify(&message), message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_pm_failures() {
+        let cases = [
+            (
+                "adb: failed to install app.apk: Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]",
+                InstallErrorCode::InsufficientStorage,
+            ),
+            (
+                "Failure [INSTALL_FAILED_UPDATE_INCOMPATIBLE: Package com.example.app signatures \
+                 do not match previously installed version; ignoring!]",
+                InstallErrorCode::SignatureMismatch,
+            ),
+            (
+                "Failure [INSTALL_FAILED_VERSION_DOWNGRADE: Downgrade detected: Update version \
+                 code 1 is older than current 2]",
+                InstallErrorCode::VersionDowngrade,
+            ),
+            (
+                "Failure [INSTALL_FAILED_NO_MATCHING_ABIS: Failed to extract native libraries, \
+                 res=-113]",
+                InstallErrorCode::NoMatchingAbis,
+            ),
+            (
+                "Failure [INSTALL_FAILED_OLDER_SDK: Failed parse during installPackageLI: \
+                 /data/app/vmdl123.tmp/base.apk (at Binary XML file line #7): Requires newer sdk \
+                 version #33 (current version is #30)]",
+                InstallErrorCode::OlderSdk,
+            ),
+            (
+                "Failure [INSTALL_PARSE_FAILED_NO_CERTIFICATES: Failed collecting certificates for \
+                 /data/app/vmdl123.tmp/base.apk]",
+                InstallErrorCode::InvalidPackage,
+            ),
+            (
+                "Failure [INSTALL_FAILED_INVALID_APK: Split lib_slice_1_apk was defined multiple \
+                 times]",
+                InstallErrorCode::InvalidPackage,
+            ),
+            (
+                "Failure [INSTALL_FAILED_USER_RESTRICTED: Install canceled by user]",
+                InstallErrorCode::UserRestricted,
+            ),
+            (
+                "Failure [INSTALL_FAILED_ABORTED: User rejected permissions]",
+                InstallErrorCode::Unknown,
+            ),
+        ];
+        for (output, code) in cases {
+            assert_eq!(InstallErrorCode::classify(output), code, "{}", output);
+        }
+    }
+
+    #[test]
+    fn classifies_adb_failures() {
+        let cases = [
+            ("error: device offline", InstallErrorCode::DeviceOffline),
+            (
+                "error: device unauthorized.\nThis adb server's $ADB_VENDOR_KEYS is not set\nTry \
+                 'adb kill-server' if that seems wrong.",
+                InstallErrorCode::Unauthorized,
+            ),
+            (
+                "adb: device 'emulator-5554' not found",
+                InstallErrorCode::DeviceNotFound,
+            ),
+            (
+                "adb: error: failed to read copy response\nadb: error: protocol fault (couldn't \
+                 read status): Connection reset by peer",
+                InstallErrorCode::ConnectionLost,
+            ),
+            (
+                "Failed to connect to the adb server at 127.0.0.1:5037: Connection refused (os \
+                 error 111)",
+                InstallErrorCode::ConnectionLost,
+            ),
+            (
+                "Command timed out after 300s: adb request",
+                InstallErrorCode::Timeout,
+            ),
+            (
+                "Command cancelled: adb request",
+                InstallErrorCode::Cancelled,
+            ),
+        ];
+        for (output, code) in cases {
+            assert_eq!(InstallErrorCode::classify(output), code, "{}", output);
+        }
+    }
+
+    #[test]
+    fn classifies_ideviceinstaller_failures() {
+        let cases = [
+            (
+                "ERROR: Install failed. Got error \"ApplicationVerificationFailed\" with code \
+                 0xe8008015: Failed to verify code signature of /private/var/installd/Library/\
+                 Caches/com.apple.mobile.installd.staging/temp.Xyz/extracted/Payload/Runner.app \
+                 : 0xe8008015 (A valid provisioning profile for this executable was not found.)",
+                InstallErrorCode::VerificationFailed,
+            ),
+            (
+                "ERROR: Install failed. Got error \"MismatchedApplicationIdentifierEntitlement\" \
+                 with code 0xe8008016: Upgrade's application-identifier entitlement string \
+                 (ABCDE12345.com.example.app) does not match installed application's \
+                 application-identifier string (FGHIJ67890.com.example.app); rejecting upgrade.",
+                InstallErrorCode::SignatureMismatch,
+            ),
+            (
+                "No device found with udid 00008030-001A2B3C4D5E6F70, is it plugged in?",
+                InstallErrorCode::DeviceNotFound,
+            ),
+        ];
+        for (output, code) in cases {
+            assert_eq!(InstallErrorCode::classify(output), code, "{}", output);
+        }
+    }
+
+    #[test]
+    fn retries_only_transient_failures() {
+        let error = InstallError::from("error: device offline".to_string());
+        assert!(error.code.is_transient());
+        assert_eq!(error.remediation, Some(Remediation::ReconnectDevice));
+
+        let error = InstallError::from("Failure [INSTALL_FAILED_OLDER_SDK]".to_string());
+        assert!(!error.code.is_transient());
+        assert_eq!(error.remediation, Some(Remediation::UseCompatibleBuild));
+    }
+}
@@ -1,15 +1,18 @@
-use glob::glob;
-use plist::Value;
-use std::{
-    io::{BufReader, Cursor, Read},
-    path::Path,
-};
+use std::fs;
 
 use log::{error, info};
+use serde_json::Value;
 
 use crate::{
-    device_adapter::i_adapter::{Device, DeviceStatus, IAdapter, ScreenRequest},
-    utils::{command_executor, env_helper::ENV_DATA},
+    device_adapter::{
+        i_adapter::{Device, DeviceStatus, IAdapter, InstallMode, InstalledApp, ScreenRequest},
+        install_error::{InstallError, InstallErrorCode, Remediation},
+    },
+    jobs::{context::JobContext, events::InstallStage},
+    utils::{
+        ipa_helper,
+        retry::{retry, Operation},
+    },
 };
 
 pub struct IosAdapter {
@@ -17,112 +20,144 @@ pub struct IosAdapter {
 }
 
 impl IosAdapter {
-    fn is_app_installed(&self, package_name: &String) -> Result<bool, String> {
+    fn is_app_installed(&self, package_name: &String, ctx: &JobContext) -> Result<bool, String> {
         info!(
             "[{}] Checking if {} is installed",
             self.device.name, package_name
         );
-        match command_executor::exec(&format!(
-            "idb list-apps --udid {} | grep {}",
-            self.device.id, package_name
-        )) {
-            Ok(_) => Ok(true),
-            Err(err) => {
-                if err.starts_with("Failed to") {
-                    return Ok(false);
-                }
-                return Err(err);
-            }
-        }
+        // Each line looks like `com.apple.Maps | Maps | system | arm64 | ...`
+        ctx.exec(
+            &self.device,
+            "idb",
+            &["list-apps", "--udid", &self.device.id],
+        )
+        .map(|output| {
+            output
+                .lines()
+                .filter_map(|line| line.split('|').next())
+                .any(|bundle_id| bundle_id.trim() == package_name)
+        })
     }
 
-    fn uninstall_app(&self, package_name: &String) -> Result<(), String> {
+    fn uninstall_app(&self, package_name: &String, ctx: &JobContext) -> Result<(), String> {
         info!("[{}] Uninstalling app {}", self.device.name, package_name);
-        match command_executor::exec(&format!(
-            "idb uninstall --udid {} {}",
-            self.device.id, package_name
-        )) {
+        match ctx.exec(
+            &self.device,
+            "idb",
+            &["uninstall", "--udid", &self.device.id, package_name],
+        ) {
             Ok(_) => Ok(()),
             Err(err) => Err(err.to_string()),
         }
     }
 
-    /// I hope I will have some time to refactor this because it's pretty shitty
-    fn get_bundle_name(&self, bundle_path: &String) -> Result<String, String> {
-        let bundle_file = Path::new(bundle_path);
-
-        if !bundle_file.exists() {
-            return Err("The given path does not exists".to_string());
-        }
-
-        let new_file = format!("{}.zip", bundle_path);
+    /// Reads the bundle identifier from the `Info.plist` of the app, shared with the other devices
+    /// installing the same bundle
+    fn get_bundle_name(&self, bundle_path: &str) -> Result<String, String> {
+        let plist = ipa_helper::read_info_plist(bundle_path)?;
+        let bundle_name = ipa_helper::bundle_identifier(&plist)
+            .map_err(|err| format!("Invalid Info.plist in {}: {}", bundle_path, err))?;
 
-        let result = match command_executor::exec(&format!("cp {} {}", bundle_path, &new_file)) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err.to_string()),
-        };
+        info!("Got bundle name: {}", &bundle_name);
+        Ok(bundle_name)
+    }
 
-        if result.is_err() {
-            return Err(result.unwrap_err());
+    /// Returns the `CFBundleVersion` and `CFBundleShortVersionString` of the installed app, [None]
+    /// if it's not installed
+    fn installed_version(
+        &self,
+        bundle_name: &str,
+        ctx: &JobContext,
+    ) -> Result<Option<(String, String)>, String> {
+        if self.device.emulator {
+            // Old-style plist, each value looks like `CFBundleVersion = "42";`
+            let output = ctx.exec(
+                &self.device,
+                "xcrun",
+                &["simctl", "appinfo", &self.device.id, bundle_name],
+            )?;
+            let value = |key: &str| {
+                output.lines().find_map(|line| {
+                    let (name, value) = line.split_once('=')?;
+                    (name.trim() == key)
+                        .then(|| value.trim().trim_end_matches(';').trim_matches('"'))
+                        .map(String::from)
+                })
+            };
+            return Ok(value("CFBundleVersion").map(|build| {
+                (
+                    build,
+                    value("CFBundleShortVersionString").unwrap_or_default(),
+                )
+            }));
         }
 
-        let extraction_path = format!(
-            "{}/{}",
-            ENV_DATA.lock().unwrap().extract_output_dir,
-            self.device.id
-        );
-        info!("Extracting application into {}", extraction_path);
-
-        let file = std::fs::File::open(&new_file).map_err(|err| err.to_string())?;
-        let mut reader = BufReader::new(&file);
-        let mut buffer = Vec::new();
-
-        let dim = reader.read_to_end(&mut buffer);
-        info!(
-            "Read {} bytes",
-            dim.map(|size| size.to_string())
-                .unwrap_or("There was an error".to_string())
-        );
-
-        let extraction_folder = Path::new(&extraction_path);
+        let workspace = ctx.device_workspace(&self.device)?;
+        let json_path = workspace.join("installed-apps.json");
+        ctx.exec(
+            &self.device,
+            "xcrun",
+            &[
+                "devicectl",
+                "device",
+                "info",
+                "apps",
+                "--device",
+                &self.device.id,
+                "--bundle-id",
+                bundle_name,
+                "--json-output",
+                &json_path.to_string_lossy(),
+            ],
+        )?;
+        let content = fs::read_to_string(&json_path)
+            .map_err(|err| format!("Failed to read {}: {}", json_path.display(), err))?;
+        let json: Value = serde_json::from_str(&content).map_err(|err| err.to_string())?;
+        let app = json["result"]["apps"]
+            .as_array()
+            .and_then(|apps| apps.first());
+        Ok(app.and_then(|app| {
+            let build = app["bundleVersion"].as_str()?;
+            let version = app["version"].as_str().unwrap_or_default();
+            Some((String::from(build), String::from(version)))
+        }))
+    }
 
-        match zip_extract::extract(Cursor::new(&buffer), &extraction_folder, true) {
-            Ok(_) => info!(
-                "Extracted zip file in {}",
-                &extraction_folder.to_str().unwrap()
-            ),
+    /// Whether the app is installed with the version of the bundle. Any failure is reported as a
+    /// different version, so that the app gets installed
+    fn is_same_version_installed(
+        &self,
+        bundle_path: &str,
+        bundle_name: &str,
+        ctx: &JobContext,
+    ) -> bool {
+        let installed = match self.installed_version(bundle_name, ctx) {
+            Ok(Some(version)) => version,
+            Ok(None) => return false,
             Err(err) => {
-                error!("Failed to extract zip file:\n{}", err.to_string());
-                return Err(err.to_string());
+                ctx.warn(
+                    &self.device,
+                    InstallStage::PackageDetection,
+                    &format!("Failed to read the installed version: {}", err),
+                );
+                return false;
             }
-        }
-
-        let info_plists = glob(
-            format!(
-                "{}/**/Info.plist",
-                &extraction_folder.as_os_str().to_str().unwrap()
-            )
-            .as_str(),
-        )
-        .expect("Failed to read glob");
-
-        let item = info_plists.min_by_key(|f| f.as_ref().unwrap().display().to_string().len());
-
-        let correct_plist = item.unwrap().unwrap().to_str().unwrap().to_string();
-
-        info!("Minimum info.plist path: {}", &correct_plist);
-
-        let info = Value::from_file(&correct_plist).expect("Must be a valid plist file");
-
-        let bundle_name = info
-            .as_dictionary()
-            .and_then(|dict| dict.get("CFBundleIdentifier"))
-            .and_then(|bundle| bundle.as_string())
-            .expect("There should be the bundle identifier key");
-
-        info!("Got bundle name: {}", &bundle_name);
+        };
+        ctx.info(
+            &self.device,
+            InstallStage::PackageDetection,
+            &format!(
+                "Installed version of {}: {} ({})",
+                bundle_name, installed.1, installed.0
+            ),
+        );
 
-        return Ok(bundle_name.to_string());
+        let Ok(plist) = ipa_helper::read_info_plist(bundle_path) else {
+            return false;
+        };
+        ipa_helper::string_value(&plist, "CFBundleVersion").as_ref() == Some(&installed.0)
+            && ipa_helper::string_value(&plist, "CFBundleShortVersionString").unwrap_or_default()
+                == installed.1
     }
 }
 
@@ -131,20 +166,44 @@ impl IAdapter for IosAdapter {
 
     fn unlock_device(&self) {}
 
-    fn open_app(&self, app_name: &String) {
-        match command_executor::exec(&format!(
-            "idb launch --udid {} {}",
-            self.device.id, &app_name
-        )) {
-            Ok(_) => info!("[{}] Launched app {}", self.device.name, &app_name),
-            Err(err) => error!(
-                "[{}] Failed to launch app {}\n{}",
-                self.device.name, &app_name, err
-            ),
+    fn open_app(&self, app: &InstalledApp, ctx: &JobContext) -> Result<(), String> {
+        let app_name = &app.package_name;
+        match ctx.exec(
+            &self.device,
+            "idb",
+            &["launch", "--udid", &self.device.id, app_name],
+        ) {
+            Ok(_) => {
+                info!("[{}] Launched app {}", self.device.name, &app_name);
+                Ok(())
+            }
+            Err(err) => {
+                error!(
+                    "[{}] Failed to launch app {}\n{}",
+                    self.device.name, &app_name, err
+                );
+                Err(format!("Failed to launch app {}: {}", &app_name, err))
+            }
         }
     }
 
-    fn send_keyevent(&self, _key_event: &String) {
+    fn is_app_running(&self, app: &InstalledApp, ctx: &JobContext) -> Result<bool, String> {
+        // Each line looks like `com.apple.Maps | Maps | system | arm64 | Running | Debuggable`
+        ctx.exec(
+            &self.device,
+            "idb",
+            &["list-apps", "--udid", &self.device.id],
+        )
+        .map(|output| {
+            output.lines().any(|line| {
+                let columns = line.split('|').map(str::trim).collect::<Vec<&str>>();
+                columns.first() == Some(&app.package_name.as_str())
+                    && columns.get(4) == Some(&"Running")
+            })
+        })
+    }
+
+    fn send_keyevent(&self, _key_event: &str) {
         todo!()
     }
 
@@ -153,31 +212,126 @@ impl IAdapter for IosAdapter {
         DeviceStatus::Awake
     }
 
-    fn install_bundle(&self, bundle_path: &String) -> Result<String, String> {
+    fn install_bundle(
+        &self,
+        bundle_path: &str,
+        ctx: &JobContext,
+    ) -> Result<InstalledApp, InstallError> {
         if !bundle_path.ends_with(".app") && !bundle_path.ends_with(".ipa") {
-            error!("Invalid bundle for ios device: {}", &bundle_path);
-            return Err(format!("Invalid bundle path: {}", &bundle_path));
+            let msg = format!("Invalid bundle path: {}", &bundle_path);
+            ctx.error(&self.device, InstallStage::Failed, &msg);
+            return Err(InstallError::from(msg));
+        }
+
+        ctx.info(
+            &self.device,
+            InstallStage::PackageDetection,
+            &format!("Reading bundle name from {}", &bundle_path),
+        );
+        let bundle_name = match self.get_bundle_name(bundle_path) {
+            Ok(bundle_name) => bundle_name,
+            Err(err) => {
+                let msg = format!("Failed to read the bundle name: {}", err);
+                ctx.error(&self.device, InstallStage::PackageDetection, &msg);
+                return Err(InstallError::from(msg));
+            }
+        };
+        ctx.info(
+            &self.device,
+            InstallStage::PackageDetection,
+            &format!("Detected bundle {}", &bundle_name),
+        );
+
+        let mode = ctx.install_mode;
+        if mode == InstallMode::SkipIfSame
+            && self.is_same_version_installed(bundle_path, &bundle_name, ctx)
+        {
+            ctx.info(
+                &self.device,
+                InstallStage::Install,
+                &format!(
+                    "The same version of {} is already installed, skipping the install",
+                    &bundle_name
+                ),
+            );
+            return Ok(InstalledApp {
+                package_name: bundle_name,
+                launcher_activity: None,
+                skipped: true,
+            });
         }
 
-        let bundle_name = self
-            .get_bundle_name(&bundle_path)
-            .expect("There should be a bundle name");
+        let clean_install = matches!(mode, InstallMode::CleanInstall | InstallMode::SkipIfSame);
+        if clean_install && self.is_app_installed(&bundle_name, ctx).unwrap_or(false) {
+            ctx.info(
+                &self.device,
+                InstallStage::Uninstall,
+                &format!("App {} is already installed, uninstalling it", &bundle_name),
+            );
+            if let Err(err) = self.uninstall_app(&bundle_name, ctx) {
+                ctx.warn(
+                    &self.device,
+                    InstallStage::Uninstall,
+                    &format!("Failed to uninstall app: {}", err),
+                );
+            }
+        }
 
-        if self.is_app_installed(&bundle_name).unwrap_or(false) {
-            _ = self.uninstall_app(&bundle_name);
+        ctx.info(
+            &self.device,
+            InstallStage::Install,
+            &format!("Installing app ({})", mode),
+        );
+        let install = || {
+            ctx.exec(
+                &self.device,
+                "idb",
+                &["install", "--udid", &self.device.id, bundle_path],
+            )
+            .map_err(|err| {
+                InstallError::from(format!("Failed to install bundle on ios device: {}", err))
+            })
+        };
+        let log = |msg: &str| ctx.warn(&self.device, InstallStage::Install, msg);
+        let mut result = retry(Operation::Install, ctx, &log, install);
+        if let Err(err) = &result {
+            // The data of the app are lost, which only the force install mode accepts
+            if err.remediation == Some(Remediation::UninstallAndRetry)
+                && mode == InstallMode::Force
+                && self.is_app_installed(&bundle_name, ctx)?
+            {
+                ctx.warn(
+                    &self.device,
+                    InstallStage::Install,
+                    &format!("Install rejected, reinstalling the app: {}", err),
+                );
+                self.uninstall_app(&bundle_name, ctx)?;
+                result = install();
+            }
         }
 
-        match command_executor::exec(&format!(
-            "idb install --udid {} {}",
-            self.device.id, &bundle_path
-        )) {
+        match result {
             Ok(_) => {
-                info!("Installed bundle on ios device");
-                return Ok(bundle_name);
+                ctx.info(
+                    &self.device,
+                    InstallStage::Install,
+                    "Installed bundle on ios device",
+                );
+                Ok(InstalledApp {
+                    package_name: bundle_name,
+                    launcher_activity: None,
+                    skipped: false,
+                })
             }
             Err(err) => {
-                error!("Failed to install bundle on ios device: {}", err);
-                return Err(format!("Failed to install bundle on ios device: {}", err));
+                let error = match err.code {
+                    InstallErrorCode::SignatureMismatch => {
+                        InstallError::signature_mismatch(&bundle_name)
+                    }
+                    _ => err,
+                };
+                ctx.error(&self.device, InstallStage::Install, &error.to_string());
+                Err(error)
             }
         }
     }
@@ -186,6 +340,10 @@ impl IAdapter for IosAdapter {
         String::from(&self.device.name)
     }
 
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+
     fn get_os_type(&self) -> crate::device_adapter::i_adapter::OsType {
         self.device.os_type
     }
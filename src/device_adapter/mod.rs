@@ -1,3 +1,4 @@
 pub mod android;
 pub mod i_adapter;
+pub mod install_error;
 pub mod ios;
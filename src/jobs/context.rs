@@ -0,0 +1,119 @@
+use std::{ffi::OsStr, fs::create_dir_all, path::PathBuf, sync::Arc};
+
+use log::{debug, error, info, warn};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    device_adapter::i_adapter::{Device, InstallMode},
+    utils::command_executor::{self, ExecOptions, OutputStream},
+};
+
+use super::{
+    events::{publish, EventLevel, InstallStage, JobEvent},
+    job::{LogLine, LogSource},
+    registry,
+};
+
+/// Contains the informations about the job that requested an operation on a device
+#[derive(Debug, Clone)]
+pub struct JobContext {
+    pub job_id: String,
+    /// Cancelled when the job has to stop, kills the commands started through [JobContext::exec]
+    pub cancel: CancellationToken,
+    /// Installs with higher priority are started first when their device is busy
+    pub priority: i32,
+    /// What the adapters do when the app is already installed
+    pub install_mode: InstallMode,
+    /// Directory reserved to the job, removed once it's finished
+    pub workspace: PathBuf,
+}
+
+impl JobContext {
+    pub fn new(
+        job_id: &str,
+        cancel: CancellationToken,
+        priority: i32,
+        install_mode: InstallMode,
+        workspace: PathBuf,
+    ) -> JobContext {
+        JobContext {
+            job_id: String::from(job_id),
+            cancel,
+            priority,
+            install_mode,
+            workspace,
+        }
+    }
+
+    /// Creates, if needed, and returns the directory in which the files generated for the device
+    /// have to be written. Since each device runs one install at a time, nothing else writes in it
+    pub fn device_workspace(&self, device: &Device) -> Result<PathBuf, String> {
+        let path = self.workspace.join(&device.id);
+        create_dir_all(&path)
+            .map_err(|err| format!("Failed to create workspace {}: {}", path.display(), err))?;
+        Ok(path)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Logs the message for the given device and publishes it as an event of the job
+    pub fn info(&self, device: &Device, stage: InstallStage, message: &str) {
+        info!("[{}] {}", device.name, message);
+        self.publish(device, stage, EventLevel::Info, message);
+    }
+
+    /// Same as [JobContext::info] but with warning level
+    pub fn warn(&self, device: &Device, stage: InstallStage, message: &str) {
+        warn!("[{}] {}", device.name, message);
+        self.publish(device, stage, EventLevel::Warning, message);
+    }
+
+    /// Same as [JobContext::info] but with error level
+    pub fn error(&self, device: &Device, stage: InstallStage, message: &str) {
+        error!("[{}] {}", device.name, message);
+        self.publish(device, stage, EventLevel::Error, message);
+    }
+
+    /// Runs the command on behalf of the job: its output is streamed into the job log and it's
+    /// killed when the job gets cancelled
+    pub fn exec<S>(&self, device: &Device, program: &str, args: &[S]) -> Result<String, String>
+    where
+        S: AsRef<OsStr>,
+    {
+        let job_id = String::from(&self.job_id);
+        let device_id = String::from(&device.id);
+        let device_name = String::from(&device.name);
+
+        let options = ExecOptions {
+            cancel: Some(self.cancel.clone()),
+            on_line: Some(Arc::new(move |stream, line| {
+                debug!("[{}] {}", device_name, line);
+                registry::append_log(&job_id, LogLine::new(Some(&device_id), stream.into(), line));
+            })),
+            ..ExecOptions::default()
+        };
+        command_executor::exec_with(program, args, &options)
+    }
+
+    /// Writes in the job log, line by line, the output of a command that didn't run through
+    /// [JobContext::exec]
+    pub fn log_output(&self, device: &Device, stream: OutputStream, output: &str) {
+        for line in output.lines() {
+            debug!("[{}] {}", device.name, line);
+            registry::append_log(
+                &self.job_id,
+                LogLine::new(Some(&device.id), stream.into(), line),
+            );
+        }
+    }
+
+    fn publish(&self, device: &Device, stage: InstallStage, level: EventLevel, message: &str) {
+        registry::append_log(
+            &self.job_id,
+            LogLine::new(Some(&device.id), LogSource::Hub, message),
+        );
+        publish(JobEvent::new(&self.job_id, device, stage, level, message));
+    }
+}
@@ -0,0 +1,84 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::device_adapter::i_adapter::Device;
+
+use super::job::now_millis;
+
+/// Number of events kept for slow subscribers before they start lagging behind
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Channel on which all the installation events are broadcasted
+pub static EVENTS: Lazy<Sender<JobEvent>> = Lazy::new(|| broadcast::channel(EVENTS_CAPACITY).0);
+
+/// Structured event emitted while a bundle is installed on a device
+#[derive(Debug, Serialize, Clone)]
+pub struct JobEvent {
+    pub job_id: String,
+    pub device_id: String,
+    pub device_name: String,
+    pub stage: InstallStage,
+    pub level: EventLevel,
+    pub message: String,
+    pub timestamp: u64,
+}
+
+/// Steps that `install_bundle_all` and `upgrade_bundle_all` go through for every device
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallStage {
+    Queued,
+    Started,
+    ApkExtraction,
+    PackageDetection,
+    Uninstall,
+    Install,
+    Launch,
+    /// Setup flow run by the upgrade jobs before installing the new version
+    Setup,
+    /// Check that the app is still running after the upgrade
+    AliveCheck,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl JobEvent {
+    pub fn new(
+        job_id: &str,
+        device: &Device,
+        stage: InstallStage,
+        level: EventLevel,
+        message: &str,
+    ) -> JobEvent {
+        JobEvent {
+            job_id: String::from(job_id),
+            device_id: String::from(&device.id),
+            device_name: String::from(&device.name),
+            stage,
+            level,
+            message: String::from(message),
+            timestamp: now_millis(),
+        }
+    }
+}
+
+/// Sends the event to all the current subscribers
+pub fn publish(event: JobEvent) {
+    // Failing to send just means that nobody is listening right now
+    _ = EVENTS.send(event);
+}
+
+/// Subscribes to all the events published from now on
+pub fn subscribe() -> Receiver<JobEvent> {
+    EVENTS.subscribe()
+}
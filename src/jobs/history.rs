@@ -0,0 +1,186 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{create_dir_all, rename, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+
+use crate::utils::env_helper::ENV_DATA;
+
+use super::job::{Job, JobStatus};
+
+/// Number of log lines stored with each snapshot of a job
+const LOG_EXCERPT_LINES: usize = 200;
+
+/// Number of snapshots appended to the journal, on top of one per job, after which it's compacted
+const COMPACTION_THRESHOLD: usize = 1000;
+
+/// Journal in which a snapshot of a job is appended every time it's recorded
+static JOURNAL: Lazy<Mutex<Option<Journal>>> = Lazy::new(|| Mutex::new(None));
+
+struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Number of jobs in the journal when it was last compacted
+    jobs: usize,
+    /// Number of snapshots appended since then
+    appended: usize,
+}
+
+/// Returns a copy of the job to record in the history, keeping only the last lines of its log
+pub fn snapshot(job: &mut Job) -> Job {
+    let log = std::mem::take(&mut job.log);
+    let mut snapshot = job.clone();
+    let excess = log.len().saturating_sub(LOG_EXCERPT_LINES);
+    snapshot.log = log.iter().skip(excess).cloned().collect();
+    job.log = log;
+    snapshot
+}
+
+/// Appends the snapshot, taken with [snapshot], to the journal. The journal is compacted once it
+/// contains too many outdated snapshots
+pub fn record(snapshot: &Job) {
+    let mut journal = JOURNAL.lock().unwrap();
+    let Some(current) = journal.as_mut() else {
+        return;
+    };
+
+    let result = serde_json::to_string(snapshot)
+        .map_err(|err| err.to_string())
+        .and_then(|line| writeln!(current.file, "{}", line).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        error!(
+            "Failed to record job {} in the history: {}",
+            snapshot.id, err
+        );
+        return;
+    }
+
+    current.appended += 1;
+    if current.appended < current.jobs + COMPACTION_THRESHOLD {
+        return;
+    }
+    let path = current.path.clone();
+    let compacted = read_journal(&path).and_then(|mut jobs| {
+        drop_expired(&mut jobs);
+        open_compacted(&path, &jobs)
+    });
+    match compacted {
+        Ok(compacted) => *journal = Some(compacted),
+        Err(err) => error!("Failed to compact the job history: {}", err),
+    }
+}
+
+/// Returns the ids of the oldest finished jobs exceeding `max_jobs`. The jobs that are not finished
+/// are always kept
+pub fn expired<'a, I>(jobs: I, max_jobs: usize) -> HashSet<String>
+where
+    I: IntoIterator<Item = &'a Job>,
+{
+    let mut finished = jobs
+        .into_iter()
+        .filter(|job| job.finished_at.is_some())
+        .collect::<Vec<&Job>>();
+    finished.sort_by_key(|job| std::cmp::Reverse(job.created_at));
+    finished
+        .iter()
+        .skip(max_jobs)
+        .map(|job| String::from(&job.id))
+        .collect()
+}
+
+/// Reads the jobs stored in the journal at `JOB_HISTORY_FILE` and opens it to record the new
+/// snapshots.
+///
+/// Only the last snapshot of each job is kept, so the journal is rewritten with one line per job.
+/// The jobs that were still running when the hub stopped are marked as interrupted
+pub fn load() -> Result<Vec<Job>, String> {
+    let path = PathBuf::from(&ENV_DATA.lock().unwrap().job_history_file);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .map_err(|err| format!("Failed to create {}: {}", parent.display(), err))?;
+    }
+
+    let mut jobs = read_journal(&path)?;
+    for job in jobs.iter_mut() {
+        if job.status == JobStatus::Running {
+            job.interrupt();
+        }
+    }
+
+    drop_expired(&mut jobs);
+
+    let journal = open_compacted(&path, &jobs)?;
+    *JOURNAL.lock().unwrap() = Some(journal);
+    info!("Loaded {} jobs from {}", jobs.len(), path.display());
+    Ok(jobs)
+}
+
+/// Reads the last snapshot of each job in the journal, the oldest jobs first
+fn read_journal(path: &Path) -> Result<Vec<Job>, String> {
+    let mut jobs = HashMap::<String, Job>::new();
+    if path.exists() {
+        let file = File::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // A line can be truncated if the hub was killed while writing it
+            match serde_json::from_str::<Job>(&line) {
+                Ok(job) => {
+                    let outdated = jobs
+                        .get(&job.id)
+                        .is_some_and(|stored| stored.revision > job.revision);
+                    if !outdated {
+                        jobs.insert(String::from(&job.id), job);
+                    }
+                }
+                Err(err) => warn!("Skipping line {} of the job history: {}", index + 1, err),
+            }
+        }
+    }
+
+    let mut jobs = jobs.into_values().collect::<Vec<Job>>();
+    jobs.sort_by_key(|job| job.created_at);
+    Ok(jobs)
+}
+
+/// Removes the [expired] jobs according to `JOB_HISTORY_MAX_JOBS`
+fn drop_expired(jobs: &mut Vec<Job>) {
+    let max_jobs = ENV_DATA.lock().unwrap().job_history_max_jobs;
+    let expired = expired(jobs.iter(), max_jobs);
+    jobs.retain(|job| !expired.contains(&job.id));
+}
+
+/// Rewrites the journal with one line per job and opens it to append the next snapshots
+fn open_compacted(path: &Path, jobs: &[Job]) -> Result<Journal, String> {
+    let compacted = path.with_extension("jsonl.tmp");
+    {
+        let mut file = File::create(&compacted)
+            .map_err(|err| format!("Failed to create {}: {}", compacted.display(), err))?;
+        for job in jobs {
+            let line = serde_json::to_string(job).map_err(|err| err.to_string())?;
+            writeln!(file, "{}", line)
+                .map_err(|err| format!("Failed to write {}: {}", compacted.display(), err))?;
+        }
+    }
+    rename(&compacted, path)
+        .map_err(|err| format!("Failed to replace {}: {}", path.display(), err))?;
+
+    let file = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+    Ok(Journal {
+        path: path.to_path_buf(),
+        file,
+        jobs: jobs.len(),
+        appended: 0,
+    })
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    device_adapter::{
+        i_adapter::{InstallMode, OsType},
+        install_error::InstallError,
+    },
+    utils::command_executor::OutputStream,
+};
+
+/// Maximum number of lines kept in the log of a job, the oldest ones are dropped first
+const MAX_LOG_LINES: usize = 5000;
+
+/// Returns the current unix timestamp in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// An installation job created for every upload
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// Jobs with higher priority go ahead in the install queue, `0` by default
+    pub priority: i32,
+    #[serde(default)]
+    pub install_mode: InstallMode,
+    /// Names of the bundles that are going to be installed
+    pub artifacts: Vec<String>,
+    /// One entry for each (device, bundle) pair targeted by the job
+    pub devices: Vec<DeviceJob>,
+    /// Errors that are not bound to a specific device (ex. device discovery failures)
+    pub errors: Vec<String>,
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+    /// Messages of the job followed by the output of the commands it ran, in order
+    pub log: VecDeque<LogLine>,
+    /// Set for the jobs upgrading an app from a previous version, see [UpgradePlan]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade: Option<UpgradePlan>,
+    /// Incremented every time the job is recorded in the history, in which the snapshots can be
+    /// written out of order
+    #[serde(default)]
+    pub revision: u64,
+    /// Last time the job was recorded in the history
+    #[serde(skip)]
+    pub recorded_at: Option<Instant>,
+    /// Number of bundles whose installation has not finished yet
+    #[serde(skip)]
+    pub pending_artifacts: usize,
+    /// Cancelled when the job is stopped through the API
+    #[serde(skip)]
+    pub cancel: CancellationToken,
+    /// Directory in which the adapters write the files of the job, see [crate::jobs::context::JobContext::device_workspace]
+    #[serde(skip)]
+    pub workspace: PathBuf,
+    /// Extraction directories created for the uploaded files
+    #[serde(skip)]
+    pub temp_dirs: Vec<String>,
+}
+
+/// Upgrade test run by the job on every device: the previous version is installed from scratch
+/// and launched, optionally seeded by the setup flow, then the new version (the artifact of the
+/// job) is installed over it and has to keep running for `alive_seconds`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpgradePlan {
+    /// Bundle of the previous version
+    pub from: String,
+    /// Name of the script in `SETUP_FLOWS_DIR` run against the previous version
+    pub setup: Option<String>,
+    pub alive_seconds: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    /// Set as soon as the job is cancelled, `finished_at` is set once every device has stopped
+    Cancelled,
+    /// The hub stopped while the job was running
+    Interrupted,
+}
+
+/// Single line of the job log
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogLine {
+    pub timestamp: u64,
+    /// Device the line refers to, [None] for the messages of the whole job
+    pub device_id: Option<String>,
+    pub source: LogSource,
+    pub line: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSource {
+    /// Message emitted by the hub itself
+    Hub,
+    Stdout,
+    Stderr,
+}
+
+impl From<OutputStream> for LogSource {
+    fn from(stream: OutputStream) -> Self {
+        match stream {
+            OutputStream::Stdout => LogSource::Stdout,
+            OutputStream::Stderr => LogSource::Stderr,
+        }
+    }
+}
+
+impl LogLine {
+    pub fn new(device_id: Option<&str>, source: LogSource, line: &str) -> Self {
+        LogLine {
+            timestamp: now_millis(),
+            device_id: device_id.map(String::from),
+            source,
+            line: String::from(line),
+        }
+    }
+}
+
+/// Status of a single bundle installation against a single device
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceJob {
+    pub device_id: String,
+    pub device_name: String,
+    pub os_type: OsType,
+    pub bundle: String,
+    pub state: DeviceJobState,
+    /// Name of the installed package, available once the install succeeded
+    pub package_name: Option<String>,
+    /// Error returned by the adapter if the install or the launch failed
+    pub error: Option<String>,
+    /// Classification of the error, with the remediation that can resolve it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_error: Option<InstallError>,
+    /// The install was skipped since the same version was already on the device
+    #[serde(default)]
+    pub skipped: bool,
+    /// Outcome of each step of the upgrade jobs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade: Option<UpgradeReport>,
+    pub started_at: Option<u64>,
+    pub installed_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+/// Steps of an [UpgradePlan] completed on a device
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UpgradeReport {
+    /// The previous version was installed and launched
+    pub previous_launched: bool,
+    /// [None] when the job has no setup flow or it didn't run
+    pub setup_succeeded: Option<bool>,
+    /// The new version was installed over the previous one and launched
+    pub upgraded: bool,
+    /// The new version was still running `alive_seconds` after its launch
+    pub alive: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceJobState {
+    Pending,
+    Installing,
+    Launching,
+    Launched,
+    Failed,
+    Cancelled,
+    /// The hub stopped before the install was done
+    Interrupted,
+}
+
+impl Job {
+    pub fn new(
+        id: String,
+        artifacts: Vec<String>,
+        priority: i32,
+        install_mode: InstallMode,
+        workspace: PathBuf,
+    ) -> Job {
+        Job {
+            id,
+            status: JobStatus::Running,
+            priority,
+            install_mode,
+            pending_artifacts: artifacts.len(),
+            artifacts,
+            devices: Vec::new(),
+            errors: Vec::new(),
+            created_at: now_millis(),
+            finished_at: None,
+            log: VecDeque::new(),
+            upgrade: None,
+            revision: 0,
+            recorded_at: None,
+            cancel: CancellationToken::new(),
+            workspace,
+            temp_dirs: Vec::new(),
+        }
+    }
+
+    /// Appends the line to the log, dropping the oldest one if it's full
+    pub fn append_log(&mut self, line: LogLine) {
+        if self.log.len() >= MAX_LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    /// Marks one of the artifacts as processed and, once all of them are done, computes the
+    /// final status of the job
+    pub fn complete_artifact(&mut self) {
+        self.pending_artifacts = self.pending_artifacts.saturating_sub(1);
+        if self.pending_artifacts > 0 {
+            return;
+        }
+
+        self.finished_at = Some(now_millis());
+        if self.status == JobStatus::Cancelled {
+            return;
+        }
+
+        let failed = !self.errors.is_empty()
+            || self.devices.is_empty()
+            || self
+                .devices
+                .iter()
+                .any(|d| d.state != DeviceJobState::Launched);
+
+        self.status = if failed {
+            JobStatus::Failed
+        } else {
+            JobStatus::Succeeded
+        };
+    }
+
+    /// Marks the job and the devices that were not done yet as interrupted, used for the jobs found
+    /// still running in the history when the hub starts
+    pub fn interrupt(&mut self) {
+        let now = now_millis();
+        self.status = JobStatus::Interrupted;
+        self.finished_at = Some(now);
+        for device in self.devices.iter_mut() {
+            if matches!(
+                device.state,
+                DeviceJobState::Pending | DeviceJobState::Installing | DeviceJobState::Launching
+            ) {
+                device.state = DeviceJobState::Interrupted;
+                device.finished_at = Some(now);
+            }
+        }
+        self.append_log(LogLine::new(
+            None,
+            LogSource::Hub,
+            "Job interrupted by a restart of the hub",
+        ));
+    }
+
+    /// Whether one of the devices targeted by the job has the given id or a name containing
+    /// `device`, ignoring the case
+    pub fn targets_device(&self, device: &str) -> bool {
+        let device = device.to_lowercase();
+        self.devices.iter().any(|d| {
+            d.device_id.to_lowercase() == device || d.device_name.to_lowercase().contains(&device)
+        })
+    }
+
+    /// Stops the job: the devices that didn't start yet are marked as cancelled while the running
+    /// ones get their commands killed
+    pub fn cancel(&mut self) {
+        self.status = JobStatus::Cancelled;
+        self.cancel.cancel();
+        for device in self.devices.iter_mut() {
+            if device.state == DeviceJobState::Pending {
+                device.state = DeviceJobState::Cancelled;
+                device.finished_at = Some(now_millis());
+            }
+        }
+        self.append_log(LogLine::new(None, LogSource::Hub, "Job cancelled"));
+    }
+
+    pub fn get_device_mut(&mut self, device_id: &str, bundle: &str) -> Option<&mut DeviceJob> {
+        self.devices
+            .iter_mut()
+            .find(|d| d.device_id == device_id && d.bundle == bundle)
+    }
+}
+
+impl DeviceJob {
+    pub fn new(device_id: String, device_name: String, os_type: OsType, bundle: String) -> Self {
+        DeviceJob {
+            device_id,
+            device_name,
+            os_type,
+            bundle,
+            state: DeviceJobState::Pending,
+            package_name: None,
+            error: None,
+            install_error: None,
+            skipped: false,
+            upgrade: None,
+            started_at: None,
+            installed_at: None,
+            finished_at: None,
+        }
+    }
+}
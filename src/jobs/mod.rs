@@ -0,0 +1,12 @@
+/// Contains the informations shared with the adapters while running a job
+pub mod context;
+/// Broadcasts the events emitted while installing the bundles
+pub mod events;
+/// Stores the jobs on disk so that they survive a restart of the hub
+pub mod history;
+/// Contains the models describing an installation job
+pub mod job;
+/// Runs the installs one at a time on each device, by priority
+pub mod queue;
+/// Keeps track of all the jobs created by the `/upload` endpoint
+pub mod registry;
@@ -0,0 +1,157 @@
+use std::{
+    cmp::Reverse,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Mutex,
+    },
+    thread,
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::device_adapter::i_adapter::Device;
+
+use super::{context::JobContext, job::now_millis};
+
+/// Contains the installs waiting for their device and the ones currently running
+static QUEUE: Lazy<Mutex<InstallQueue>> = Lazy::new(|| Mutex::new(InstallQueue::default()));
+
+/// Single operation of a job against a device
+#[derive(Debug, Serialize, Clone)]
+pub struct QueueEntry {
+    pub job_id: String,
+    pub device_id: String,
+    pub device_name: String,
+    pub bundle: String,
+    pub priority: i32,
+    pub enqueued_at: u64,
+    pub started_at: Option<u64>,
+    /// Keeps the installs with the same priority in arrival order
+    #[serde(skip)]
+    sequence: u64,
+}
+
+/// Snapshot of the queue returned by the `/queue` endpoint
+#[derive(Debug, Serialize)]
+pub struct QueueSnapshot {
+    pub running: Vec<QueueEntry>,
+    /// Sorted in the order in which the installs are going to start
+    pub pending: Vec<QueueEntry>,
+}
+
+struct PendingInstall {
+    entry: QueueEntry,
+    ctx: JobContext,
+    task: Box<dyn FnOnce() + Send>,
+}
+
+#[derive(Default)]
+struct InstallQueue {
+    pending: Vec<PendingInstall>,
+    running: Vec<QueueEntry>,
+    next_sequence: u64,
+}
+
+/// Releases the device once the task is done, even if it panicked, and notifies whoever is waiting
+/// for it
+struct RunningGuard {
+    sequence: u64,
+    done: SyncSender<()>,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        QUEUE
+            .lock()
+            .unwrap()
+            .running
+            .retain(|entry| entry.sequence != self.sequence);
+        dispatch();
+        _ = self.done.send(());
+    }
+}
+
+/// Queues `task` to be run against the device on behalf of the job in `ctx`.
+///
+/// Each device runs a single task at a time: the pending ones are started by priority, highest
+/// first, and then by arrival order. Tasks of cancelled jobs are started right away, without
+/// waiting for the device, since they are expected to return immediately.
+///
+/// The returned [Receiver] is notified once the task is done
+pub fn enqueue<F>(ctx: &JobContext, device: &Device, bundle: &str, task: F) -> Receiver<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let (done, receiver) = mpsc::sync_channel(1);
+    {
+        let mut queue = QUEUE.lock().unwrap();
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.pending.push(PendingInstall {
+            entry: QueueEntry {
+                job_id: String::from(&ctx.job_id),
+                device_id: String::from(&device.id),
+                device_name: String::from(&device.name),
+                bundle: String::from(bundle),
+                priority: ctx.priority,
+                enqueued_at: now_millis(),
+                started_at: None,
+                sequence,
+            },
+            ctx: ctx.clone(),
+            task: Box::new(move || {
+                let _guard = RunningGuard { sequence, done };
+                task();
+            }),
+        });
+    }
+    dispatch();
+    receiver
+}
+
+/// Starts every pending task whose device is free
+pub fn dispatch() {
+    let mut queue = QUEUE.lock().unwrap();
+    queue
+        .pending
+        .sort_by_key(|pending| (Reverse(pending.entry.priority), pending.entry.sequence));
+
+    let mut index = 0;
+    while index < queue.pending.len() {
+        let pending = &queue.pending[index];
+        let cancelled = pending.ctx.is_cancelled();
+        let device_busy = queue
+            .running
+            .iter()
+            .any(|entry| entry.device_id == pending.entry.device_id);
+        if device_busy && !cancelled {
+            index += 1;
+            continue;
+        }
+
+        let mut pending = queue.pending.remove(index);
+        if !cancelled {
+            pending.entry.started_at = Some(now_millis());
+            queue.running.push(pending.entry);
+        }
+        thread::spawn(pending.task);
+    }
+}
+
+/// Returns the installs currently running and the ones waiting for their device
+pub fn snapshot() -> QueueSnapshot {
+    let mut queue = QUEUE.lock().unwrap();
+    queue
+        .pending
+        .sort_by_key(|pending| (Reverse(pending.entry.priority), pending.entry.sequence));
+
+    QueueSnapshot {
+        running: queue.running.clone(),
+        pending: queue
+            .pending
+            .iter()
+            .map(|pending| pending.entry.clone())
+            .collect(),
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+
+use crate::{device_adapter::i_adapter::InstallMode, utils::env_helper::ENV_DATA};
+
+use super::{
+    context::JobContext,
+    history,
+    job::{DeviceJob, DeviceJobState, Job, JobStatus, LogLine},
+};
+
+/// Minimum time between two snapshots of a job recorded in the history for changes that are not a
+/// [milestone]
+const RECORD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Contains all the jobs known by the hub, including the ones restored from the history, indexed
+/// by their id
+pub static JOBS: Lazy<Mutex<HashMap<String, Job>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Creates a new job for the given artifacts and returns its id. The workspace of the job is
+/// `{EXTRACT_DEFAULT_DIR}/jobs/{id}`
+pub fn create_job(artifacts: Vec<String>, priority: i32, install_mode: InstallMode) -> String {
+    let id = Uuid::new_v4().to_string();
+    let workspace = Path::new(&ENV_DATA.lock().unwrap().extract_output_dir)
+        .join("jobs")
+        .join(&id);
+    let mut job = Job::new(
+        String::from(&id),
+        artifacts,
+        priority,
+        install_mode,
+        workspace,
+    );
+    let snapshot = take_snapshot(&mut job, true);
+    JOBS.lock().unwrap().insert(String::from(&id), job);
+    if let Some(snapshot) = snapshot {
+        history::record(&snapshot);
+    }
+    id
+}
+
+/// Loads the jobs stored in the history, see [history::load]
+pub fn restore_history() -> Result<(), String> {
+    let jobs = history::load()?;
+    let mut registry = JOBS.lock().unwrap();
+    for job in jobs {
+        registry.insert(String::from(&job.id), job);
+    }
+    Ok(())
+}
+
+/// Returns the jobs matching `f`, newest first
+pub fn find_jobs<F>(f: F) -> Vec<Job>
+where
+    F: Fn(&Job) -> bool,
+{
+    let mut jobs = JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|job| f(job))
+        .cloned()
+        .collect::<Vec<Job>>();
+    jobs.sort_by_key(|job| std::cmp::Reverse(job.created_at));
+    jobs
+}
+
+/// Returns a snapshot of the job with the given id
+pub fn get_job(job_id: &str) -> Option<Job> {
+    JOBS.lock().unwrap().get(job_id).cloned()
+}
+
+/// Runs `f` against the job with the given id, if it exists, and records the new snapshot in the
+/// history. The changes that are not a [milestone] are recorded at most every [RECORD_INTERVAL]
+pub fn update_job<F>(job_id: &str, f: F)
+where
+    F: FnOnce(&mut Job),
+{
+    let snapshot = JOBS.lock().unwrap().get_mut(job_id).and_then(|job| {
+        let before = milestone(job);
+        f(job);
+        let changed = milestone(job) != before;
+        take_snapshot(job, changed)
+    });
+    // Written once the jobs are unlocked, the revision of the snapshot orders them in the history
+    if let Some(snapshot) = snapshot {
+        history::record(&snapshot);
+    }
+}
+
+/// State of the job whose changes are recorded in the history right away: its status, its errors
+/// and the states of its devices
+fn milestone(job: &Job) -> (JobStatus, Option<u64>, usize, Vec<DeviceJobState>) {
+    (
+        job.status,
+        job.finished_at,
+        job.errors.len(),
+        job.devices.iter().map(|device| device.state).collect(),
+    )
+}
+
+/// Returns the snapshot of the job to record in the history, if it's a milestone or the last one
+/// is older than [RECORD_INTERVAL]
+fn take_snapshot(job: &mut Job, milestone: bool) -> Option<Job> {
+    let recent = job
+        .recorded_at
+        .is_some_and(|recorded_at| recorded_at.elapsed() < RECORD_INTERVAL);
+    if !milestone && recent {
+        return None;
+    }
+    job.revision += 1;
+    job.recorded_at = Some(Instant::now());
+    Some(history::snapshot(job))
+}
+
+/// Returns the context to give to the adapters working on behalf of the job
+pub fn context(job_id: &str) -> Option<JobContext> {
+    JOBS.lock().unwrap().get(job_id).map(|job| {
+        JobContext::new(
+            &job.id,
+            job.cancel.clone(),
+            job.priority,
+            job.install_mode,
+            job.workspace.clone(),
+        )
+    })
+}
+
+/// Cancels the job with the given id and returns its snapshot. Fails if the job doesn't exist or
+/// it's already finished
+pub fn cancel_job(job_id: &str) -> Result<Job, CancelError> {
+    let mut jobs = JOBS.lock().unwrap();
+    let job = jobs.get_mut(job_id).ok_or(CancelError::NotFound)?;
+    if job.status != JobStatus::Running {
+        return Err(CancelError::NotRunning);
+    }
+    job.cancel();
+    let snapshot = take_snapshot(job, true);
+    let job = job.clone();
+    drop(jobs);
+    if let Some(snapshot) = snapshot {
+        history::record(&snapshot);
+    }
+    Ok(job)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CancelError {
+    NotFound,
+    NotRunning,
+}
+
+/// Marks one of the artifacts of the job as processed. Once the job is done, returns its workspace
+/// and the extraction directories of its uploads, which have to be removed, and drops the oldest
+/// finished jobs beyond `JOB_HISTORY_MAX_JOBS`
+pub fn complete_artifact(job_id: &str) -> Vec<String> {
+    let max_jobs = ENV_DATA.lock().unwrap().job_history_max_jobs;
+    let mut jobs = JOBS.lock().unwrap();
+    let Some(job) = jobs.get_mut(job_id) else {
+        return Vec::new();
+    };
+
+    job.complete_artifact();
+    let snapshot = take_snapshot(job, true);
+    let mut dirs = Vec::new();
+    if job.finished_at.is_some() {
+        dirs = std::mem::take(&mut job.temp_dirs);
+        dirs.push(job.workspace.to_string_lossy().to_string());
+        for id in history::expired(jobs.values(), max_jobs) {
+            jobs.remove(&id);
+        }
+    }
+    drop(jobs);
+
+    if let Some(snapshot) = snapshot {
+        history::record(&snapshot);
+    }
+    dirs
+}
+
+/// Adds a new device entry to the job
+pub fn add_device(job_id: &str, device: DeviceJob) {
+    update_job(job_id, |job| job.devices.push(device));
+}
+
+/// Appends the line to the log of the job. The history is not updated, the log is stored with the
+/// next snapshot of the job
+pub fn append_log(job_id: &str, line: LogLine) {
+    if let Some(job) = JOBS.lock().unwrap().get_mut(job_id) {
+        job.append_log(line);
+    }
+}
+
+/// Runs `f` against the entry of the job bound to the given device and bundle
+pub fn update_device<F>(job_id: &str, device_id: &str, bundle: &str, f: F)
+where
+    F: FnOnce(&mut DeviceJob),
+{
+    update_job(job_id, |job| {
+        if let Some(device) = job.get_device_mut(device_id, bundle) {
+            f(device);
+        }
+    });
+}
@@ -13,10 +13,12 @@ use tracing::Level;
 use dialoguer::Confirm;
 
 use log::{error, info, warn};
-use utils::{command_executor::command_exists, env_helper::ENV_DATA};
+use utils::{command_executor::command_exists, discovery::DiscoveryBackend, env_helper::ENV_DATA};
 
 mod api;
+mod artifacts;
 mod device_adapter;
+mod jobs;
 mod utils;
 
 #[tokio::main]
@@ -35,13 +37,26 @@ async fn main() -> Result<(), Error> {
         }
     }
 
+    if let Err(err) = jobs::registry::restore_history() {
+        error!("Could not load the job history: {}", err);
+        exit(1);
+    }
+
+    if let Err(err) = artifacts::store::load() {
+        error!("Could not load the artifact store: {}", err);
+        exit(1);
+    }
+
+    let max_upload_size = ENV_DATA.lock().unwrap().max_upload_size;
     let router = initialize_router()
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
         )
-        .layer(DefaultBodyLimit::disable());
+        .layer(DefaultBodyLimit::max(
+            usize::try_from(max_upload_size).unwrap_or(usize::MAX),
+        ));
 
     let address = SocketAddr::from(([0, 0, 0, 0], 42069));
     tracing::info!("Listening on {}", &address);
@@ -107,39 +122,32 @@ fn check_extraction_path() -> Result<(), Error> {
                 exit(1);
             }
         }
-        return Ok(());
+        Ok(())
     }
 }
 
 /// Checks whether all the required binaries are installed and present in PATH
 fn validate_depdencies() {
-    // FIXME: should remove this dependency to use just adb and idb
-    // Used to find all connected devices
-    if command_exists(&"flutter".to_string()).is_err() {
+    // Used to find all connected devices only when explicitly requested, otherwise adb and idb
+    // are used directly
+    let discovery_backend = ENV_DATA.lock().unwrap().discovery_backend;
+    if discovery_backend == DiscoveryBackend::Flutter && command_exists("flutter").is_err() {
         exit(1);
     }
 
     // Used to interact with android devices
-    if command_exists(&"adb".to_string()).is_err() {
+    if command_exists("adb").is_err() {
         exit(1);
     }
 
     // Used to manage android appbundles
-    if command_exists(&"bundletool".to_string()).is_err() {
+    if command_exists("bundletool").is_err() {
         exit(1);
     }
 
-    // Used to interact with ios devices just like adb
-    if command_exists(&"idb".to_string()).is_err() {
-        exit(1);
-    }
-
-    // Used to extract packagename from an apk
-    if command_exists(&"aapt2".to_string()).is_err() {
-        exit(1);
-    }
-
-    if command_exists(&"tar".to_string()).is_err() {
-        exit(1);
+    // Used to interact with ios devices just like adb. It's available only on macOS, so hubs
+    // without it can still handle android devices
+    if command_exists("idb").is_err() {
+        warn!("idb is not available, iOS devices won't be handled");
     }
 }
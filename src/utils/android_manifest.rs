@@ -0,0 +1,907 @@
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek},
+    path::Path,
+};
+
+use zip::ZipArchive;
+
+use super::{apks_helper, bundle_kind::BundleKind, zip_helper::read_entry};
+
+/// Path of the manifest inside an apk, encoded as binary xml
+const APK_MANIFEST: &str = "AndroidManifest.xml";
+/// Path of the manifest of the base module inside an aab, encoded as a protobuf `XmlNode`
+const AAB_MANIFEST: &str = "base/manifest/AndroidManifest.xml";
+
+// Chunk types of the binary xml format, see `ResourceTypes.h` in the Android framework
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+const RES_XML_END_ELEMENT_TYPE: u16 = 0x0103;
+const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+
+// Types of the attribute values of the binary xml format
+const TYPE_REFERENCE: u8 = 0x01;
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_DEC: u8 = 0x10;
+const TYPE_INT_HEX: u8 = 0x11;
+const TYPE_INT_BOOLEAN: u8 = 0x12;
+
+/// Deepest element accepted in a manifest, the real ones don't go past a handful of levels. The
+/// elements are dropped recursively, so a deeper tree could overflow the stack
+const MAX_DEPTH: usize = 64;
+
+const UTF8_FLAG: u32 = 1 << 8;
+const NO_INDEX: u32 = 0xffffffff;
+
+/// Attributes read from the manifest, identified by their resource id when the apk was built
+/// without their names (ex. by obfuscators)
+const ATTRIBUTE_IDS: [(u32, &str); 6] = [
+    (0x01010003, "name"),
+    (0x0101000e, "enabled"),
+    (0x0101020c, "minSdkVersion"),
+    (0x0101021b, "versionCode"),
+    (0x0101021c, "versionName"),
+    (0x01010270, "targetSdkVersion"),
+];
+
+/// Content of the `AndroidManifest.xml` of an app
+#[derive(Debug, Clone, Default)]
+pub struct AndroidManifest {
+    pub package_name: String,
+    pub version_code: Option<String>,
+    pub version_name: Option<String>,
+    pub min_sdk: Option<String>,
+    pub target_sdk: Option<String>,
+    pub permissions: Vec<String>,
+    /// Fully qualified name of the activity started by the launcher, if the app has one
+    pub launcher_activity: Option<String>,
+}
+
+/// Element of the manifest, decoded either from binary xml or from protobuf. Attributes are
+/// stored without their namespace
+#[derive(Debug, Default)]
+struct XmlElement {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<XmlElement>,
+}
+
+impl XmlElement {
+    fn attribute(&self, name: &str) -> Option<String> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| String::from(value))
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlElement> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+}
+
+/// Reads the manifest of an android bundle: apk, apk set, directory of split apks or aab. Only the
+/// manifest is read from the archives, nothing is extracted
+pub fn read(bundle_path: &str) -> Result<AndroidManifest, String> {
+    let root = match BundleKind::from_path(Path::new(bundle_path)) {
+        Some(BundleKind::Apk) => apk_manifest(open_archive(bundle_path)?)?,
+        Some(BundleKind::SplitApks) => {
+            let apks = apks_helper::list_split_apks(bundle_path)?;
+            apk_manifest(open_archive(&apks[0])?)?
+        }
+        Some(BundleKind::Apks) => {
+            let base_apk = apks_helper::read_base_apk(bundle_path)?;
+            let archive = ZipArchive::new(Cursor::new(base_apk))
+                .map_err(|err| format!("Invalid base apk in {}: {}", bundle_path, err))?;
+            apk_manifest(archive)?
+        }
+        Some(BundleKind::Aab) => {
+            let content = read_entry(&mut open_archive(bundle_path)?, AAB_MANIFEST)?;
+            proto_node(&content, 0)?.ok_or(format!("Empty manifest in {}", bundle_path))?
+        }
+        _ => return Err(format!("Unsupported android bundle {}", bundle_path)),
+    };
+
+    AndroidManifest::from_element(&root)
+        .map_err(|err| format!("Invalid manifest in {}: {}", bundle_path, err))
+}
+
+impl AndroidManifest {
+    fn from_element(root: &XmlElement) -> Result<AndroidManifest, String> {
+        if root.name != "manifest" {
+            return Err(format!("Unexpected root element {}", root.name));
+        }
+        let package_name = root
+            .attribute("package")
+            .filter(|package| !package.is_empty())
+            .ok_or("Missing package name")?;
+
+        let uses_sdk = root.children("uses-sdk").next();
+        let permissions = root
+            .children
+            .iter()
+            .filter(|child| child.name.starts_with("uses-permission"))
+            .filter_map(|child| child.attribute("name"))
+            .collect();
+        let launcher_activity = root
+            .children("application")
+            .flat_map(|application| application.children.iter())
+            .filter(|child| child.name == "activity" || child.name == "activity-alias")
+            .filter(|activity| activity.attribute("enabled").as_deref() != Some("false"))
+            .find(|activity| is_launcher(activity))
+            .and_then(|activity| activity.attribute("name"))
+            .map(|name| qualify_class_name(&package_name, &name));
+
+        Ok(AndroidManifest {
+            version_code: root.attribute("versionCode"),
+            version_name: root.attribute("versionName"),
+            min_sdk: uses_sdk.and_then(|sdk| sdk.attribute("minSdkVersion")),
+            target_sdk: uses_sdk.and_then(|sdk| sdk.attribute("targetSdkVersion")),
+            permissions,
+            launcher_activity,
+            package_name,
+        })
+    }
+}
+
+/// Whether the activity has an intent filter for the `MAIN` action and the `LAUNCHER` category
+fn is_launcher(activity: &XmlElement) -> bool {
+    activity.children("intent-filter").any(|filter| {
+        let has = |element: &str, value: &str| {
+            filter
+                .children(element)
+                .any(|child| child.attribute("name").as_deref() == Some(value))
+        };
+        has("action", "android.intent.action.MAIN")
+            && has("category", "android.intent.category.LAUNCHER")
+    })
+}
+
+/// Activity names can be relative to the package (ex. `.MainActivity`)
+fn qualify_class_name(package_name: &str, name: &str) -> String {
+    if name.starts_with('.') {
+        format!("{}{}", package_name, name)
+    } else if !name.contains('.') {
+        format!("{}.{}", package_name, name)
+    } else {
+        String::from(name)
+    }
+}
+
+fn open_archive(path: &str) -> Result<ZipArchive<BufReader<File>>, String> {
+    let file = File::open(path).map_err(|err| format!("Failed to open {}: {}", path, err))?;
+    ZipArchive::new(BufReader::new(file))
+        .map_err(|err| format!("Invalid archive {}: {}", path, err))
+}
+
+fn apk_manifest<R: Read + Seek>(mut archive: ZipArchive<R>) -> Result<XmlElement, String> {
+    binary_xml(&read_entry(&mut archive, APK_MANIFEST)?)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or("Truncated binary xml".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or("Truncated binary xml".to_string())
+}
+
+/// Decodes the binary xml format used for the manifests of the apks. The file is a sequence of
+/// chunks: a string pool, a map of the attributes resource ids and the start/end of each element
+fn binary_xml(data: &[u8]) -> Result<XmlElement, String> {
+    if u16_at(data, 0)? != RES_XML_TYPE {
+        return Err("Not a binary xml file".to_string());
+    }
+
+    let mut strings = Vec::<String>::new();
+    let mut resource_ids = Vec::<u32>::new();
+    let mut stack = Vec::<XmlElement>::new();
+    let mut root = None;
+
+    let mut offset = u16_at(data, 2)? as usize;
+    while offset + 8 <= data.len() {
+        let chunk_type = u16_at(data, offset)?;
+        let header_size = u16_at(data, offset + 2)? as usize;
+        let size = u32_at(data, offset + 4)? as usize;
+        let chunk = data
+            .get(offset..offset + size)
+            .filter(|_| size >= 8)
+            .ok_or("Truncated binary xml chunk")?;
+
+        match chunk_type {
+            RES_STRING_POOL_TYPE => strings = string_pool(chunk)?,
+            RES_XML_RESOURCE_MAP_TYPE => {
+                resource_ids = (header_size..size)
+                    .step_by(4)
+                    .map(|position| u32_at(chunk, position))
+                    .collect::<Result<Vec<u32>, String>>()?
+            }
+            RES_XML_START_ELEMENT_TYPE => {
+                let string = |index: u32| strings.get(index as usize).cloned().unwrap_or_default();
+
+                // The element header is followed by `ResXMLTree_attrExt`
+                let mut element = XmlElement {
+                    name: string(u32_at(chunk, header_size + 4)?),
+                    ..Default::default()
+                };
+                let attribute_start = u16_at(chunk, header_size + 8)? as usize;
+                let attribute_size = u16_at(chunk, header_size + 10)? as usize;
+                let attribute_count = u16_at(chunk, header_size + 12)? as usize;
+
+                for index in 0..attribute_count {
+                    let attribute = header_size + attribute_start + index * attribute_size;
+                    let name_index = u32_at(chunk, attribute + 4)?;
+                    let raw_value = u32_at(chunk, attribute + 8)?;
+                    let data_type = *chunk.get(attribute + 15).ok_or("Truncated attribute")?;
+                    let data = u32_at(chunk, attribute + 16)?;
+
+                    let mut name = string(name_index);
+                    if name.is_empty() {
+                        let id = resource_ids.get(name_index as usize);
+                        name = ATTRIBUTE_IDS
+                            .iter()
+                            .find(|(known, _)| Some(known) == id)
+                            .map(|(_, name)| name.to_string())
+                            .unwrap_or_default();
+                    }
+                    let value = match data_type {
+                        TYPE_STRING => string(data),
+                        TYPE_INT_DEC => (data as i32).to_string(),
+                        TYPE_INT_HEX => format!("0x{:x}", data),
+                        TYPE_INT_BOOLEAN => (data != 0).to_string(),
+                        TYPE_REFERENCE => format!("@0x{:08x}", data),
+                        _ if raw_value != NO_INDEX => string(raw_value),
+                        _ => data.to_string(),
+                    };
+                    element.attributes.push((name, value));
+                }
+                if stack.len() >= MAX_DEPTH {
+                    return Err("Binary xml elements nested too deeply".to_string());
+                }
+                stack.push(element);
+            }
+            RES_XML_END_ELEMENT_TYPE => {
+                let element = stack.pop().ok_or("Unbalanced binary xml elements")?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => root = Some(element),
+                }
+            }
+            _ => {}
+        }
+        offset += size;
+    }
+
+    root.ok_or("No root element in the binary xml".to_string())
+}
+
+/// Decodes the strings of a `ResStringPool` chunk, encoded either in UTF-8 or in UTF-16
+fn string_pool(chunk: &[u8]) -> Result<Vec<String>, String> {
+    let header_size = u16_at(chunk, 2)? as usize;
+    let count = u32_at(chunk, 8)? as usize;
+    let utf8 = u32_at(chunk, 16)? & UTF8_FLAG != 0;
+    let strings_start = u32_at(chunk, 20)? as usize;
+
+    // Each string takes at least its 4 bytes offset, a larger count is invalid anyway
+    let mut strings = Vec::with_capacity(count.min(chunk.len() / 4));
+    for index in 0..count {
+        let mut position = strings_start + u32_at(chunk, header_size + index * 4)? as usize;
+        let string = if utf8 {
+            // The length in UTF-16 characters comes before the length in bytes, both take two
+            // bytes when the high bit of the first one is set
+            let mut length = || -> Result<usize, String> {
+                let first = *chunk.get(position).ok_or("Truncated string pool")? as usize;
+                position += 1;
+                if first & 0x80 == 0 {
+                    return Ok(first);
+                }
+                let second = *chunk.get(position).ok_or("Truncated string pool")? as usize;
+                position += 1;
+                Ok(((first & 0x7f) << 8) | second)
+            };
+            length()?;
+            let length = length()?;
+            let bytes = chunk
+                .get(position..position + length)
+                .ok_or("Truncated string pool")?;
+            String::from_utf8_lossy(bytes).to_string()
+        } else {
+            let mut length = u16_at(chunk, position)? as usize;
+            position += 2;
+            if length & 0x8000 != 0 {
+                length = ((length & 0x7fff) << 16) | u16_at(chunk, position)? as usize;
+                position += 2;
+            }
+            let units = (0..length)
+                .map(|unit| u16_at(chunk, position + unit * 2))
+                .collect::<Result<Vec<u16>, String>>()?;
+            String::from_utf16_lossy(&units)
+        };
+        strings.push(string);
+    }
+    Ok(strings)
+}
+
+/// Field of a protobuf message, only the wire types used by `Resources.proto` are supported
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Iterates over the fields of a protobuf message
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> ProtoReader<'a> {
+        ProtoReader { data, position: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.position).ok_or("Truncated protobuf")?;
+            self.position += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Invalid protobuf varint".to_string())
+    }
+
+    fn skip(&mut self, length: usize) -> Result<&'a [u8], String> {
+        // The length comes from the message, it can be anything
+        let bytes = self
+            .position
+            .checked_add(length)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or("Truncated protobuf")?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, ProtoValue<'a>)>, String> {
+        if self.position >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => self.skip(8).map(|_| ProtoValue::Fixed)?,
+            2 => {
+                let length = self.varint()? as usize;
+                ProtoValue::Bytes(self.skip(length)?)
+            }
+            5 => self.skip(4).map(|_| ProtoValue::Fixed)?,
+            wire_type => return Err(format!("Unsupported protobuf wire type {}", wire_type)),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// Decodes an `aapt.pb.XmlNode` found at the given depth, returns [None] for text nodes
+fn proto_node(data: &[u8], depth: usize) -> Result<Option<XmlElement>, String> {
+    if depth >= MAX_DEPTH {
+        return Err("Protobuf elements nested too deeply".to_string());
+    }
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, value)) = reader.next_field()? {
+        if let (1, ProtoValue::Bytes(element)) = (field, value) {
+            return proto_element(element, depth).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Decodes an `aapt.pb.XmlElement`
+fn proto_element(data: &[u8], depth: usize) -> Result<XmlElement, String> {
+    let mut element = XmlElement::default();
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            (3, ProtoValue::Bytes(name)) => {
+                element.name = String::from_utf8_lossy(name).to_string()
+            }
+            (4, ProtoValue::Bytes(attribute)) => {
+                element.attributes.push(proto_attribute(attribute)?)
+            }
+            (5, ProtoValue::Bytes(child)) => element.children.extend(proto_node(child, depth + 1)?),
+            _ => {}
+        }
+    }
+    Ok(element)
+}
+
+/// Decodes an `aapt.pb.XmlAttribute`. The value is the one of the source file, the compiled one
+/// is used only when it's missing
+fn proto_attribute(data: &[u8]) -> Result<(String, String), String> {
+    let mut name = String::new();
+    let mut value = String::new();
+    let mut compiled = None;
+
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, field_value)) = reader.next_field()? {
+        match (field, field_value) {
+            (2, ProtoValue::Bytes(bytes)) => name = String::from_utf8_lossy(bytes).to_string(),
+            (3, ProtoValue::Bytes(bytes)) => value = String::from_utf8_lossy(bytes).to_string(),
+            (6, ProtoValue::Bytes(item)) => compiled = proto_item(item)?,
+            _ => {}
+        }
+    }
+
+    if value.is_empty() {
+        value = compiled.unwrap_or_default();
+    }
+    Ok((name, value))
+}
+
+/// Decodes the strings and the integer/boolean primitives of an `aapt.pb.Item`
+fn proto_item(data: &[u8]) -> Result<Option<String>, String> {
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, value)) = reader.next_field()? {
+        match (field, value) {
+            // `String` and `RawString` messages contain only the value
+            (2 | 3, ProtoValue::Bytes(string)) => {
+                let mut string = ProtoReader::new(string);
+                while let Some((field, value)) = string.next_field()? {
+                    if let (1, ProtoValue::Bytes(bytes)) = (field, value) {
+                        return Ok(Some(String::from_utf8_lossy(bytes).to_string()));
+                    }
+                }
+            }
+            (7, ProtoValue::Bytes(primitive)) => {
+                let mut primitive = ProtoReader::new(primitive);
+                while let Some((field, value)) = primitive.next_field()? {
+                    match (field, value) {
+                        (6, ProtoValue::Varint(int)) => return Ok(Some((int as i32).to_string())),
+                        (7, ProtoValue::Varint(hex)) => return Ok(Some(format!("0x{:x}", hex))),
+                        (8, ProtoValue::Varint(boolean)) => {
+                            return Ok(Some((boolean != 0).to_string()))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io::Write};
+
+    use uuid::Uuid;
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+
+    /// Value of an attribute of the binary xml fixtures
+    enum Value {
+        String(&'static str),
+        Int(u32),
+        Boolean(bool),
+    }
+
+    /// Builds binary xml documents like aapt2 does for the manifests of the apks
+    struct BinaryXml {
+        /// The first string is empty, for the attributes known only by their resource id
+        strings: Vec<&'static str>,
+        resource_ids: Vec<u32>,
+        elements: Vec<u8>,
+    }
+
+    fn chunk(chunk_type: u16, header: &[u8], body: &[u8]) -> Vec<u8> {
+        let header_size = 8 + header.len();
+        let mut chunk = Vec::new();
+        chunk.extend(chunk_type.to_le_bytes());
+        chunk.extend((header_size as u16).to_le_bytes());
+        chunk.extend(((header_size + body.len()) as u32).to_le_bytes());
+        chunk.extend(header);
+        chunk.extend(body);
+        chunk
+    }
+
+    impl BinaryXml {
+        fn new() -> BinaryXml {
+            BinaryXml {
+                strings: vec![""],
+                resource_ids: Vec::new(),
+                elements: Vec::new(),
+            }
+        }
+
+        fn string(&mut self, string: &'static str) -> u32 {
+            match self.strings.iter().position(|known| *known == string) {
+                Some(index) => index as u32,
+                None => {
+                    self.strings.push(string);
+                    self.strings.len() as u32 - 1
+                }
+            }
+        }
+
+        fn start(&mut self, name: &'static str, attributes: &[(&'static str, Value)]) -> &mut Self {
+            let mut body = Vec::new();
+            // `ResXMLTree_attrExt`: namespace, name, attribute start, size and count, then the
+            // indexes of the id, class and style attributes
+            body.extend(NO_INDEX.to_le_bytes());
+            body.extend(self.string(name).to_le_bytes());
+            body.extend(20u16.to_le_bytes());
+            body.extend(20u16.to_le_bytes());
+            body.extend((attributes.len() as u16).to_le_bytes());
+            body.extend([0u8; 6]);
+            for (name, value) in attributes {
+                let (raw_value, data_type, data) = match value {
+                    Value::String(string) => {
+                        let index = self.string(string);
+                        (index, TYPE_STRING, index)
+                    }
+                    Value::Int(int) => (NO_INDEX, TYPE_INT_DEC, *int),
+                    Value::Boolean(boolean) => (NO_INDEX, TYPE_INT_BOOLEAN, *boolean as u32),
+                };
+                body.extend(NO_INDEX.to_le_bytes());
+                body.extend(self.string(name).to_le_bytes());
+                body.extend(raw_value.to_le_bytes());
+                body.extend(8u16.to_le_bytes());
+                body.extend([0, data_type]);
+                body.extend(data.to_le_bytes());
+            }
+            self.elements
+                .extend(chunk(RES_XML_START_ELEMENT_TYPE, &[0u8; 8], &body));
+            self
+        }
+
+        fn end(&mut self, name: &'static str) -> &mut Self {
+            let mut body = Vec::from(NO_INDEX.to_le_bytes());
+            body.extend(self.string(name).to_le_bytes());
+            self.elements
+                .extend(chunk(RES_XML_END_ELEMENT_TYPE, &[0u8; 8], &body));
+            self
+        }
+
+        fn build(&self, utf8: bool) -> Vec<u8> {
+            let mut offsets = Vec::new();
+            let mut data = Vec::new();
+            for string in &self.strings {
+                offsets.extend((data.len() as u32).to_le_bytes());
+                if utf8 {
+                    data.extend([string.chars().count() as u8, string.len() as u8]);
+                    data.extend(string.as_bytes());
+                    data.push(0);
+                } else {
+                    let units = string.encode_utf16().collect::<Vec<u16>>();
+                    data.extend((units.len() as u16).to_le_bytes());
+                    data.extend(units.iter().flat_map(|unit| unit.to_le_bytes()));
+                    data.extend([0, 0]);
+                }
+            }
+            let mut header = Vec::new();
+            header.extend((self.strings.len() as u32).to_le_bytes());
+            header.extend(0u32.to_le_bytes());
+            header.extend((if utf8 { UTF8_FLAG } else { 0 }).to_le_bytes());
+            header.extend((28 + offsets.len() as u32).to_le_bytes());
+            header.extend(0u32.to_le_bytes());
+            offsets.extend(data);
+
+            let mut body = chunk(RES_STRING_POOL_TYPE, &header, &offsets);
+            let resource_ids = self
+                .resource_ids
+                .iter()
+                .flat_map(|id| id.to_le_bytes())
+                .collect::<Vec<u8>>();
+            body.extend(chunk(RES_XML_RESOURCE_MAP_TYPE, &[], &resource_ids));
+            body.extend(&self.elements);
+            chunk(RES_XML_TYPE, &[], &body)
+        }
+    }
+
+    /// Manifest with a launcher activity relative to the package, whose `versionCode` is known
+    /// only by its resource id
+    fn apk_manifest_fixture(utf8: bool) -> Vec<u8> {
+        let mut xml = BinaryXml::new();
+        xml.resource_ids.push(0x0101021b);
+        xml.start(
+            "manifest",
+            &[
+                ("", Value::Int(42)),
+                ("versionName", Value::String("1.2.3")),
+                ("package", Value::String("com.example.app")),
+            ],
+        )
+        .start(
+            "uses-sdk",
+            &[
+                ("minSdkVersion", Value::Int(24)),
+                ("targetSdkVersion", Value::Int(33)),
+            ],
+        )
+        .end("uses-sdk")
+        .start(
+            "uses-permission",
+            &[("name", Value::String("android.permission.INTERNET"))],
+        )
+        .end("uses-permission")
+        .start("application", &[])
+        .start("activity", &[("name", Value::String(".SettingsActivity"))])
+        .end("activity")
+        .start(
+            "activity",
+            &[
+                ("name", Value::String(".MainActivity")),
+                ("enabled", Value::Boolean(true)),
+            ],
+        )
+        .start("intent-filter", &[])
+        .start(
+            "action",
+            &[("name", Value::String("android.intent.action.MAIN"))],
+        )
+        .end("action")
+        .start(
+            "category",
+            &[("name", Value::String("android.intent.category.LAUNCHER"))],
+        )
+        .end("category")
+        .end("intent-filter")
+        .end("activity")
+        .end("application")
+        .end("manifest");
+        xml.build(utf8)
+    }
+
+    fn proto_varint(mut number: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        while number >= 0x80 {
+            data.push((number & 0x7f) as u8 | 0x80);
+            number >>= 7;
+        }
+        data.push(number as u8);
+        data
+    }
+
+    fn proto_bytes(field: u64, bytes: &[u8]) -> Vec<u8> {
+        let mut data = proto_varint(field << 3 | 2);
+        data.extend(proto_varint(bytes.len() as u64));
+        data.extend(bytes);
+        data
+    }
+
+    fn proto_attribute(name: &str, value: &str, compiled: Option<Vec<u8>>) -> Vec<u8> {
+        let mut attribute = proto_bytes(2, name.as_bytes());
+        attribute.extend(proto_bytes(3, value.as_bytes()));
+        if let Some(item) = compiled {
+            attribute.extend(proto_bytes(6, &item));
+        }
+        proto_bytes(4, &attribute)
+    }
+
+    /// `aapt.pb.Item` containing a `Primitive` with the given field
+    fn proto_primitive(field: u64, value: u64) -> Vec<u8> {
+        let mut primitive = proto_varint(field << 3);
+        primitive.extend(proto_varint(value));
+        proto_bytes(7, &primitive)
+    }
+
+    fn proto_element(name: &str, attributes: Vec<Vec<u8>>, children: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut element = proto_bytes(3, name.as_bytes());
+        element.extend(attributes.concat());
+        for child in children {
+            element.extend(proto_bytes(5, &child));
+        }
+        // Wrapped in an `XmlNode`
+        proto_bytes(1, &element)
+    }
+
+    /// Manifest of an aab whose launcher is an activity alias, after a disabled launcher activity
+    fn aab_manifest_fixture() -> Vec<u8> {
+        let intent_filter = || {
+            proto_element(
+                "intent-filter",
+                vec![],
+                vec![
+                    proto_element(
+                        "action",
+                        vec![proto_attribute("name", "android.intent.action.MAIN", None)],
+                        vec![],
+                    ),
+                    proto_element(
+                        "category",
+                        vec![proto_attribute(
+                            "name",
+                            "android.intent.category.LAUNCHER",
+                            None,
+                        )],
+                        vec![],
+                    ),
+                ],
+            )
+        };
+        let application = proto_element(
+            "application",
+            vec![],
+            vec![
+                proto_element(
+                    "activity",
+                    vec![
+                        proto_attribute("name", "com.example.app.OldLauncher", None),
+                        proto_attribute("enabled", "", Some(proto_primitive(8, 0))),
+                    ],
+                    vec![intent_filter()],
+                ),
+                proto_element(
+                    "activity-alias",
+                    vec![proto_attribute("name", "com.example.app.Launcher", None)],
+                    vec![intent_filter()],
+                ),
+            ],
+        );
+        proto_element(
+            "manifest",
+            vec![
+                proto_attribute("package", "com.example.app", None),
+                proto_attribute("versionCode", "", Some(proto_primitive(6, 7))),
+                proto_attribute("versionName", "2.0", None),
+            ],
+            vec![application],
+        )
+    }
+
+    /// Writes an archive containing the given entries in the temporary directory
+    fn write_archive(extension: &str, entries: &[(&str, &[u8])]) -> String {
+        let path = env::temp_dir().join(format!("dhh-test-{}.{}", Uuid::new_v4(), extension));
+        let mut writer = ZipWriter::new(fs::File::create(&path).unwrap());
+        for (name, content) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn reads_binary_xml_manifests() {
+        for utf8 in [false, true] {
+            let root = binary_xml(&apk_manifest_fixture(utf8)).unwrap();
+            let manifest = AndroidManifest::from_element(&root).unwrap();
+            assert_eq!(manifest.package_name, "com.example.app");
+            assert_eq!(manifest.version_code.as_deref(), Some("42"));
+            assert_eq!(manifest.version_name.as_deref(), Some("1.2.3"));
+            assert_eq!(manifest.min_sdk.as_deref(), Some("24"));
+            assert_eq!(manifest.target_sdk.as_deref(), Some("33"));
+            assert_eq!(manifest.permissions, vec!["android.permission.INTERNET"]);
+            assert_eq!(
+                manifest.launcher_activity.as_deref(),
+                Some("com.example.app.MainActivity")
+            );
+        }
+    }
+
+    #[test]
+    fn reads_protobuf_manifests() {
+        let root = proto_node(&aab_manifest_fixture(), 0).unwrap().unwrap();
+        let manifest = AndroidManifest::from_element(&root).unwrap();
+        assert_eq!(manifest.package_name, "com.example.app");
+        assert_eq!(manifest.version_code.as_deref(), Some("7"));
+        assert_eq!(manifest.version_name.as_deref(), Some("2.0"));
+        assert_eq!(
+            manifest.launcher_activity.as_deref(),
+            Some("com.example.app.Launcher")
+        );
+    }
+
+    #[test]
+    fn reads_manifests_from_bundles() {
+        let apk = write_archive("apk", &[(APK_MANIFEST, &apk_manifest_fixture(true))]);
+        let aab = write_archive("aab", &[(AAB_MANIFEST, &aab_manifest_fixture())]);
+        let apk_manifest = read(&apk);
+        let aab_manifest = read(&aab);
+        fs::remove_file(&apk).unwrap();
+        fs::remove_file(&aab).unwrap();
+
+        assert_eq!(apk_manifest.unwrap().version_code.as_deref(), Some("42"));
+        assert_eq!(aab_manifest.unwrap().version_code.as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn rejects_invalid_bundles() {
+        let not_a_zip = env::temp_dir().join(format!("dhh-test-{}.apk", Uuid::new_v4()));
+        fs::write(&not_a_zip, b"not a zip").unwrap();
+        let without_manifest = write_archive("apk", &[("classes.dex", b"dex")]);
+        let not_a_zip = not_a_zip.to_string_lossy().to_string();
+        let results = [read(&not_a_zip), read(&without_manifest)];
+        fs::remove_file(&not_a_zip).unwrap();
+        fs::remove_file(&without_manifest).unwrap();
+
+        assert!(results.iter().all(Result::is_err));
+    }
+
+    #[test]
+    fn rejects_truncated_binary_xml() {
+        let data = apk_manifest_fixture(false);
+        for length in 0..data.len() {
+            assert!(binary_xml(&data[..length]).is_err(), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_binary_xml() {
+        let data = apk_manifest_fixture(false);
+        // Every 4 bytes field set to its maximum value in turn, sizes and counts included
+        for offset in (0..data.len() - 4).step_by(4) {
+            let mut corrupted = data.clone();
+            corrupted[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+            let _ = binary_xml(&corrupted).and_then(|root| AndroidManifest::from_element(&root));
+        }
+
+        // String pool claiming billions of strings
+        let mut corrupted = data.clone();
+        corrupted[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(binary_xml(&corrupted).is_err());
+        assert!(binary_xml(b"not a binary xml").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_protobuf() {
+        let data = aab_manifest_fixture();
+        for length in 0..data.len() {
+            let _ = proto_node(&data[..length], 0)
+                .map(|root| root.map(|root| AndroidManifest::from_element(&root)));
+        }
+
+        // Length prefix larger than the message, then larger than the address space
+        assert!(proto_node(&[0x0a, 0x10, 0x1a], 0).is_err());
+        let mut huge = vec![0x0a];
+        huge.extend([0xff; 9]);
+        huge.push(0x01);
+        assert!(proto_node(&huge, 0).is_err());
+        // Varint that never ends
+        assert!(proto_node(&[0x08; 11].map(|byte| byte | 0x80), 0).is_err());
+    }
+
+    #[test]
+    fn rejects_deeply_nested_manifests() {
+        let mut node = proto_element("leaf", vec![], vec![]);
+        for _ in 0..100_000 {
+            node = proto_element("a", vec![], vec![node]);
+        }
+        assert!(proto_node(&node, 0).is_err());
+
+        let mut xml = BinaryXml::new();
+        for _ in 0..100_000 {
+            xml.start("a", &[]);
+        }
+        for _ in 0..100_000 {
+            xml.end("a");
+        }
+        assert!(binary_xml(&xml.build(false)).is_err());
+    }
+
+    #[test]
+    fn rejects_unexpected_manifests() {
+        let mut xml = BinaryXml::new();
+        xml.start("resources", &[]).end("resources");
+        let root = binary_xml(&xml.build(false)).unwrap();
+        assert!(AndroidManifest::from_element(&root).is_err());
+
+        let mut xml = BinaryXml::new();
+        xml.start("manifest", &[("versionCode", Value::Int(1))])
+            .end("manifest");
+        let root = binary_xml(&xml.build(true)).unwrap();
+        assert!(AndroidManifest::from_element(&root).is_err());
+
+        // End of an element that was never started
+        let mut xml = BinaryXml::new();
+        xml.end("manifest");
+        assert!(binary_xml(&xml.build(false)).is_err());
+    }
+}
@@ -1,84 +1,109 @@
 use std::{
-    fs::{read_dir, remove_dir_all},
-    path::Path,
+    fs::{read_dir, write, File},
+    io::{BufReader, Read, Seek},
+    path::{Path, PathBuf},
 };
 
-use log::{error, info};
+use log::{info, warn};
+use zip::ZipArchive;
 
-use super::{command_executor, env_helper::ENV_DATA};
+use super::{command_executor, zip_helper};
 
-/// Extracts the apks file in the path given and returns the app package name
-pub fn extract_package_name(apks_path: &String) -> Result<String, String> {
-    info!("Extracting {}", apks_path);
-    let extraction_directory = extract_apks(apks_path)?;
+/// Returns the name of the apk containing the manifest of the app inside an apk set
+fn base_apk_name<R: Read + Seek>(archive: &ZipArchive<R>) -> Option<String> {
+    let mut apks = archive
+        .file_names()
+        .filter(|name| name.ends_with(".apk"))
+        .collect::<Vec<&str>>();
+    apks.sort();
 
-    let path = Path::new(&extraction_directory).join("splits");
-    let total_path = path.into_os_string().into_string().unwrap();
+    // `splits/base-master.apk` contains the manifest, the other splits only resources and native
+    // code. Sets built in universal or standalone mode contain complete apks instead
+    apks.iter()
+        .find(|name| **name == "splits/base-master.apk")
+        .or(apks.iter().find(|name| name.starts_with("standalones/")))
+        .or(apks.first())
+        .map(|name| name.to_string())
+}
 
-    let dir_content = read_dir(&total_path).map_err(|e| e.to_string())?;
+/// Reads the base apk of the apk set in memory, without extracting the others
+pub fn read_base_apk(apks_path: &str) -> Result<Vec<u8>, String> {
+    let file =
+        File::open(apks_path).map_err(|err| format!("Failed to open {}: {}", apks_path, err))?;
+    let mut archive = ZipArchive::new(BufReader::new(file))
+        .map_err(|err| format!("Invalid apk set {}: {}", apks_path, err))?;
+    let name = base_apk_name(&archive).ok_or(format!("No apk files found in {}", apks_path))?;
 
-    let apk_file_path = dir_content
-        .filter(|e| match e {
-            Ok(entry) => {
-                let p = entry.path().into_os_string().into_string().unwrap();
-                return p.ends_with(".apk");
-            }
-            Err(_) => false,
-        })
-        .map(|e| e.unwrap().path().into_os_string().into_string().unwrap())
-        .collect::<Vec<String>>()
-        .first()
-        .unwrap()
-        .to_owned();
+    zip_helper::read_entry(&mut archive, &name)
+        .map_err(|err| format!("Failed to read the base apk of {}: {}", apks_path, err))
+}
 
-    return command_executor::exec(&format!("aapt2 dump packagename {}", &apk_file_path))
-        .map(|res| {
-            match remove_dir_all(&extraction_directory) {
-                Ok(_) => info!("Removed directory {}", &extraction_directory),
-                Err(err) => error!(
-                    "Failed to remove directory {}: {}",
-                    &extraction_directory, err
-                ),
-            }
-            res.trim().to_owned()
-        })
-        .map_err(|err| err.to_string());
+/// Writes the base apk of the apk set inside `workspace`, for the tools that need a file
+pub fn extract_base_apk(apks_path: &str, workspace: &Path) -> Result<PathBuf, String> {
+    let path = workspace.join("base.apk");
+    write(&path, read_base_apk(apks_path)?)
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+    info!(
+        "Extracted base apk of {} into {}",
+        apks_path,
+        path.display()
+    );
+    Ok(path)
 }
 
-fn extract_apks(apks_path: &String) -> Result<String, String> {
-    let path = Path::new(apks_path);
-    if !path.exists() {
-        return Err("Apks file does not exsits".to_string());
-    }
+/// Lists the names of the files inside the archive (apk, apk set or aab)
+pub fn archive_entries(path: &str) -> Result<Vec<String>, String> {
+    let file = File::open(path).map_err(|err| format!("Failed to open {}: {}", path, err))?;
+    let archive = ZipArchive::new(BufReader::new(file))
+        .map_err(|err| format!("Invalid archive {}: {}", path, err))?;
+    Ok(archive.file_names().map(String::from).collect())
+}
 
-    let extraction_directory =
-        path.file_name().map_or("temp_file".to_string(), |name| {
-            match name.to_os_string().into_string() {
-                Ok(name) => {
-                    let cleared_name = name.replace(".apk", "");
-                    format!(
-                        "{}/{}",
-                        ENV_DATA.lock().unwrap().extract_output_dir,
-                        cleared_name
-                    )
-                }
-                Err(_) => "temp_file".to_string(),
-            }
+/// Returns the SHA-256 of the certificate that signed the apk or the bundle. `apksigner` is used
+/// when available since it handles every signature scheme, `keytool` reads only jar signatures
+pub fn certificate_sha256(path: &str) -> Option<String> {
+    let digest = command_executor::exec("apksigner", &["verify", "--print-certs", path])
+        .ok()
+        .and_then(|output| {
+            output
+                .lines()
+                .find(|line| line.contains("certificate SHA-256 digest:"))
+                .and_then(|line| line.rsplit(':').next())
+                .map(|digest| digest.trim().to_string())
+        })
+        .or_else(|| {
+            command_executor::exec("keytool", &["-printcert", "-jarfile", path])
+                .ok()?
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("SHA256:"))
+                .map(|digest| digest.trim().replace(':', "").to_lowercase())
         });
 
-    command_executor::exec(&format!("mkdir {}", extraction_directory))
-        .expect("Could not create directory");
+    if digest.is_none() {
+        warn!("Could not read the signing certificate of {}", path);
+    }
+    digest
+}
+
+/// Lists the apk files contained in the given directory, with the base apk as the first item
+pub fn list_split_apks(directory: &str) -> Result<Vec<String>, String> {
+    let mut apks = read_dir(directory)
+        .map_err(|err| format!("Failed to read directory {}: {}", directory, err))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "apk"))
+        .map(|path| path.to_str().unwrap().to_string())
+        .collect::<Vec<String>>();
+
+    if apks.is_empty() {
+        return Err(format!("No apk files found in {}", directory));
+    }
 
-    command_executor::exec(&format!(
-        "tar -xvf {} -C {}",
-        apks_path, extraction_directory
-    ))
-    .map(|_| {
-        info!("Extracted apks in path {}", extraction_directory);
-        extraction_directory
-    })
-    .map_err(|err| {
-        error!("Failed to extract apks: {}", err.to_string());
-        err.to_string()
-    })
+    // Splits are named `split_*.apk`, so the base is the only apk without that prefix
+    apks.sort_by_key(|path| {
+        Path::new(path)
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("split"))
+    });
+    Ok(apks)
 }
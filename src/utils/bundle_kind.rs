@@ -0,0 +1,106 @@
+use std::{fs::read_dir, path::Path};
+
+use crate::device_adapter::i_adapter::OsType;
+
+/// Kind of file that can be uploaded to the hub
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BundleKind {
+    /// Android app bundle, the apks are built with bundletool for each device
+    Aab,
+    /// Single android apk
+    Apk,
+    /// Apk set already built by `bundletool build-apks`
+    Apks,
+    /// Directory containing the base apk and its splits
+    SplitApks,
+    /// iOS application archive
+    Ipa,
+    /// Uncompressed iOS `.app` directory
+    App,
+    /// Zipped iOS `.app` directory (ex. `Runner.app.zip`)
+    ZippedApp,
+    /// Zipped directory of split apks (ex. `app.splits.zip`)
+    ZippedSplitApks,
+    /// Zip archive containing one or more of the other kinds
+    Archive,
+}
+
+impl BundleKind {
+    /// Gets the kind of the bundle from its file name, returns [None] if it's not supported
+    pub fn from_file_name(file_name: &str) -> Option<BundleKind> {
+        let name = file_name.to_lowercase();
+        if name.ends_with(".app.zip") {
+            return Some(BundleKind::ZippedApp);
+        }
+        if name.ends_with(".splits.zip") {
+            return Some(BundleKind::ZippedSplitApks);
+        }
+
+        let extension = name.rsplit_once('.').map(|(_, ext)| ext)?;
+        match extension {
+            "aab" => Some(BundleKind::Aab),
+            "apk" => Some(BundleKind::Apk),
+            "apks" => Some(BundleKind::Apks),
+            "ipa" => Some(BundleKind::Ipa),
+            "zip" => Some(BundleKind::Archive),
+            _ => None,
+        }
+    }
+
+    /// Gets the kind of the bundle at the given path. Directories are recognized as [BundleKind::App]
+    /// if their name ends with `.app` or as [BundleKind::SplitApks] if they contain apk files
+    pub fn from_path(path: &Path) -> Option<BundleKind> {
+        let file_name = path.file_name()?.to_str()?;
+        if !path.is_dir() {
+            return BundleKind::from_file_name(file_name);
+        }
+
+        if file_name.to_lowercase().ends_with(".app") {
+            return Some(BundleKind::App);
+        }
+
+        let contains_apks = read_dir(path)
+            .ok()?
+            .any(|entry| entry.is_ok_and(|e| e.path().extension().is_some_and(|ext| ext == "apk")));
+        if contains_apks {
+            return Some(BundleKind::SplitApks);
+        }
+        None
+    }
+
+    /// Extension of the file, including the `.app` or `.splits` suffix for zipped directories
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BundleKind::Aab => "aab",
+            BundleKind::Apk => "apk",
+            BundleKind::Apks => "apks",
+            BundleKind::SplitApks => "splits",
+            BundleKind::Ipa => "ipa",
+            BundleKind::App => "app",
+            BundleKind::ZippedApp => "app.zip",
+            BundleKind::ZippedSplitApks => "splits.zip",
+            BundleKind::Archive => "zip",
+        }
+    }
+
+    /// Whether the bundle can be given as is to the adapters or has to be extracted first
+    pub fn is_installable(&self) -> bool {
+        !matches!(
+            self,
+            BundleKind::ZippedApp | BundleKind::ZippedSplitApks | BundleKind::Archive
+        )
+    }
+
+    /// Returns the [OsType] of the devices that can install this kind of bundle
+    pub fn os_type(&self) -> OsType {
+        match self {
+            BundleKind::Aab
+            | BundleKind::Apk
+            | BundleKind::Apks
+            | BundleKind::SplitApks
+            | BundleKind::ZippedSplitApks => OsType::Android,
+            BundleKind::Ipa | BundleKind::App | BundleKind::ZippedApp => OsType::Ios,
+            BundleKind::Archive => OsType::Invalid,
+        }
+    }
+}
@@ -1,60 +1,232 @@
-use std::process::{Command, Stdio};
+use std::{
+    ffi::{OsStr, OsString},
+    future::pending,
+    process::{Command, Stdio},
+    sync::{mpsc, Arc},
+    time::Duration,
+};
 
 use log::error;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command as AsyncCommand,
+    runtime::{Builder, Runtime},
+};
+use tokio_util::sync::CancellationToken;
 
-fn get_command_components(command: &String) -> Vec<String> {
-    command
-        .split(" ")
-        .map(|s| String::from(s.trim()))
-        .collect::<Vec<String>>()
+use super::env_helper::ENV_DATA;
+
+/// Runtime driving the commands started from synchronous code, such as the installation threads
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("command-executor")
+        .enable_all()
+        .build()
+        .expect("Failed to build the command executor runtime")
+});
+
+/// Stream on which a command printed a line
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
 }
 
-/// Executes the command given and returns its output
-pub fn exec(command: &String) -> Result<String, String> {
-    let data = get_command_components(command);
+/// Called for each line printed by a command while it's still running
+pub type LineHandler = Arc<dyn Fn(OutputStream, &str) + Send + Sync>;
 
-    let mut shell_command = &mut Command::new(&data[0]);
-    for i in 1..data.len() {
-        shell_command = (*shell_command).arg(&data[i]);
-    }
+/// Options applied to a single command
+#[derive(Clone, Default)]
+pub struct ExecOptions {
+    /// The command is killed if it's still running after this time. When [None] the value of
+    /// `COMMAND_TIMEOUT` is used
+    pub timeout: Option<Duration>,
+    /// The command is killed as soon as the token is cancelled
+    pub cancel: Option<CancellationToken>,
+    pub on_line: Option<LineHandler>,
+}
+
+/// Executes `program` with the given arguments and returns its output.
+///
+/// The arguments are passed as they are, without going through a shell: there's no need to escape
+/// them, but pipes and redirections are not available. Filter the output in Rust instead.
+pub fn exec<S>(program: &str, args: &[S]) -> Result<String, String>
+where
+    S: AsRef<OsStr>,
+{
+    exec_with(program, args, &ExecOptions::default())
+}
+
+/// Same as [exec] but with the given [ExecOptions].
+///
+/// The command runs on a dedicated runtime and the current thread waits for it, so it can be used
+/// both from plain threads and from `spawn_blocking`
+pub fn exec_with<S>(program: &str, args: &[S], options: &ExecOptions) -> Result<String, String>
+where
+    S: AsRef<OsStr>,
+{
+    let program = String::from(program);
+    let args = args
+        .iter()
+        .map(|arg| arg.as_ref().to_os_string())
+        .collect::<Vec<OsString>>();
+    let options = options.clone();
+
+    let (sender, receiver) = mpsc::sync_channel(1);
+    RUNTIME.spawn(async move {
+        _ = sender.send(exec_async(&program, &args, &options).await);
+    });
+    receiver
+        .recv()
+        .map_err(|err| format!("Command executor stopped unexpectedly: {}", err))?
+}
+
+/// Spawns the command and waits for it, forwarding every line it prints to
+/// [ExecOptions::on_line]. The child process is killed if it times out or gets cancelled
+pub async fn exec_async<S>(
+    program: &str,
+    args: &[S],
+    options: &ExecOptions,
+) -> Result<String, String>
+where
+    S: AsRef<OsStr>,
+{
+    let description = describe(program, args);
+    let timeout = options
+        .timeout
+        .unwrap_or_else(|| ENV_DATA.lock().unwrap().command_timeout);
+
+    let mut child = AsyncCommand::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to spawn {}: {}", description, err))?;
+
+    let stdout = read_lines(
+        child.stdout.take(),
+        OutputStream::Stdout,
+        options.on_line.clone(),
+    );
+    let stderr = read_lines(
+        child.stderr.take(),
+        OutputStream::Stderr,
+        options.on_line.clone(),
+    );
+    let cancelled = async {
+        match &options.cancel {
+            Some(token) => token.cancelled().await,
+            None => pending().await,
+        }
+    };
 
-    let result = shell_command.output();
-    match result {
-        Ok(d) => {
-            if !d.status.success() {
-                let err = String::from_utf8(d.stderr).expect("Invalid string");
-                return Err(format!("Failed to execute command: {}\n{}", command, err));
+    let finished = tokio::time::timeout(timeout, async {
+        tokio::join!(stdout, stderr, child.wait())
+    });
+
+    let result = tokio::select! {
+        result = finished => {
+            match result {
+                Ok((stdout, stderr, Ok(status))) => {
+                    if !status.success() {
+                        return Err(format!(
+                            "Failed to execute command: {}\n{}",
+                            description, stderr
+                        ));
+                    }
+                    return Ok(stdout);
+                }
+                Ok((_, _, Err(err))) => format!("Failed to wait for {}: {}", description, err),
+                Err(_) => format!(
+                    "Command timed out after {}s: {}",
+                    timeout.as_secs(),
+                    description
+                ),
             }
-            let output = String::from_utf8(d.stdout).expect("Invalid string");
-            return Ok(output);
         }
-        Err(err) => Err(err.to_string()),
+        _ = cancelled => format!("Command cancelled: {}", description),
+    };
+
+    if let Err(err) = child.kill().await {
+        error!("Failed to kill {}: {}", description, err);
     }
+    Err(result)
 }
 
-pub fn command_exists(command: &String) -> Result<(), ()> {
-    let components = get_command_components(command);
+/// Reads the output of the command line by line, returning all of it once the stream is closed
+async fn read_lines<R>(
+    reader: Option<R>,
+    stream: OutputStream,
+    on_line: Option<LineHandler>,
+) -> String
+where
+    R: AsyncRead + Unpin,
+{
+    let mut output = String::new();
+    let Some(reader) = reader else {
+        return output;
+    };
 
-    if components.len() != 1 {
-        error!("invalid command: {}", command);
-        return Err(());
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buffer);
+                if let Some(handler) = &on_line {
+                    handler(stream, line.trim_end());
+                }
+                output.push_str(&line);
+            }
+        }
     }
+    output
+}
 
-    let command = &components[0];
+/// Formats the command for logging purposes. The description ends up in the jobs returned by the
+/// API, so the passwords given as `pass:{password}` (ex. `--ks-pass=pass:secret`) are redacted
+fn describe<S>(program: &str, args: &[S]) -> String
+where
+    S: AsRef<OsStr>,
+{
+    let mut description = String::from(program);
+    for arg in args {
+        description.push(' ');
+        let arg = arg.as_ref().to_string_lossy();
+        match arg.find("pass:") {
+            Some(index) => {
+                description.push_str(&arg[..index + "pass:".len()]);
+                description.push_str("***");
+            }
+            None => description.push_str(&arg),
+        }
+    }
+    description
+}
 
-    return Command::new("which")
-        .arg(command)
+/// Checks whether the given program is available in PATH
+pub fn command_exists(program: &str) -> Result<(), ()> {
+    Command::new("which")
+        .arg(program)
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .output()
-        .map(|output| {
+        .map_err(|err| {
+            error!("Failed to spawn `{}`: {}", program, err);
+        })
+        .and_then(|output| {
             if !output.status.success() {
-                error!("`{}` was not found! Check your PATH!", command);
+                error!("`{}` was not found! Check your PATH!", program);
+                return Err(());
             }
-            ()
+            Ok(())
         })
-        .map_err(|err| {
-            error!("Failed to spawn `{}`: {}", command, err.to_string());
-            ()
-        });
 }
@@ -1,114 +1,444 @@
 use std::{
-    path::Path,
-    process::Command,
-    thread::{self, JoinHandle},
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Arc},
+    thread,
+    time::Duration,
 };
 
-use log::{error, info};
+use log::{error, info, warn};
 
-use crate::device_adapter::i_adapter::{get_adapter, DecodedDevice, Device, IAdapter, OsType};
-
-/// Find all devices with the same os defined in filter. If filter is [None], all device types will
-/// be returned
-pub fn find_devices(filter: Option<OsType>) -> Vec<Box<dyn IAdapter>> {
-    let bytes = Command::new("flutter")
-        .arg("devices")
-        .arg("--machine")
-        .output()
-        .expect("Something went wrong")
-        .stdout;
-
-    let json_content = String::from_utf8(bytes).expect("The obtained string is not valid");
+use super::{
+    bundle_kind::BundleKind,
+    discovery::discover_devices,
+    env_helper::ENV_DATA,
+    retry::{retry, Operation},
+};
+use crate::{
+    device_adapter::{
+        i_adapter::{
+            get_adapter, Device, DeviceFilter, IAdapter, InstallMode, InstalledApp, OsType,
+        },
+        install_error::InstallError,
+    },
+    jobs::{
+        context::JobContext,
+        events::InstallStage,
+        job::{
+            now_millis, DeviceJob, DeviceJobState, LogLine, LogSource, UpgradePlan, UpgradeReport,
+        },
+        queue, registry,
+    },
+};
 
-    let data: Vec<DecodedDevice> =
-        serde_json::from_str::<Vec<DecodedDevice>>(&json_content).expect("Invalid json");
+/// Find all devices matching the given filter. An empty [DeviceFilter] returns all the devices
+/// connected
+pub fn find_devices(filter: &DeviceFilter) -> Result<Vec<Box<dyn IAdapter>>, String> {
+    let backend = ENV_DATA.lock().unwrap().discovery_backend;
 
-    let devices: Vec<Box<dyn IAdapter>> = data
-        .iter()
-        .map(|d| Device::from_parsed(d))
-        .filter(|d| match filter {
-            None => true,
-            Some(os) => d.os_type == os,
-        })
+    let devices: Vec<Box<dyn IAdapter>> = discover_devices(backend)?
+        .into_iter()
         .filter(|d| d.os_type != OsType::Invalid)
-        .map(|d| get_adapter(d))
+        .filter(|d| filter.matches(d))
+        .map(get_adapter)
         .collect();
 
-    return devices;
+    Ok(devices)
+}
+
+/// Returned by the steps that find the job cancelled
+fn cancelled() -> InstallError {
+    InstallError::from(String::from("Job cancelled"))
+}
+
+/// Marks the job entry of the device as started, fails if the job is cancelled
+fn start_device(
+    adapter: &dyn IAdapter,
+    bundle_path: &str,
+    ctx: &JobContext,
+    message: &str,
+) -> Result<(), InstallError> {
+    let device = adapter.get_device();
+    let job_id = ctx.job_id.as_str();
+
+    if ctx.is_cancelled() {
+        registry::update_device(job_id, &device.id, bundle_path, |d| {
+            d.state = DeviceJobState::Cancelled;
+            d.finished_at = Some(now_millis());
+        });
+        return Err(cancelled());
+    }
+
+    registry::update_device(job_id, &device.id, bundle_path, |d| {
+        d.state = DeviceJobState::Installing;
+        d.started_at = Some(now_millis());
+    });
+    ctx.info(device, InstallStage::Started, message);
+    Ok(())
+}
+
+/// Records the outcome in the job entry of the device
+fn finish_device(
+    adapter: &dyn IAdapter,
+    bundle_path: &str,
+    ctx: &JobContext,
+    result: &Result<(), InstallError>,
+    message: &str,
+) {
+    let device = adapter.get_device();
+    registry::update_device(&ctx.job_id, &device.id, bundle_path, |d| {
+        match result {
+            Ok(_) => d.state = DeviceJobState::Launched,
+            Err(_) if ctx.is_cancelled() => d.state = DeviceJobState::Cancelled,
+            Err(err) => {
+                d.state = DeviceJobState::Failed;
+                d.error = Some(err.to_string());
+                d.install_error = Some(err.clone());
+            }
+        }
+        d.finished_at = Some(now_millis());
+    });
+
+    match result {
+        Ok(_) => ctx.info(device, InstallStage::Completed, message),
+        Err(_) if ctx.is_cancelled() => {
+            ctx.warn(device, InstallStage::Cancelled, "Installation cancelled")
+        }
+        Err(err) => ctx.error(device, InstallStage::Failed, &err.to_string()),
+    }
+}
+
+/// Installs the bundle on the device and launches it. The progress is reported in the job entry
+/// of `entry_bundle`
+fn install_and_launch(
+    adapter: &dyn IAdapter,
+    bundle_path: &str,
+    entry_bundle: &str,
+    ctx: &JobContext,
+) -> Result<InstalledApp, InstallError> {
+    let device = adapter.get_device();
+    let app = adapter.install_bundle(bundle_path, ctx)?;
+    registry::update_device(&ctx.job_id, &device.id, entry_bundle, |d| {
+        d.state = DeviceJobState::Launching;
+        d.package_name = Some(String::from(&app.package_name));
+        d.skipped = app.skipped;
+        d.installed_at = Some(now_millis());
+    });
+    if ctx.is_cancelled() {
+        return Err(cancelled());
+    }
+    ctx.info(
+        device,
+        InstallStage::Launch,
+        &format!("Launching {}", &app.package_name),
+    );
+    let log = |msg: &str| ctx.warn(device, InstallStage::Launch, msg);
+    retry(Operation::Launch, ctx, &log, || adapter.open_app(&app, ctx))?;
+    Ok(app)
 }
 
-fn install_bundle(adapter: &dyn IAdapter, bundle_path: &String) -> Result<(), String> {
-    return adapter
-        .install_bundle(bundle_path)
-        .map(|package_name| {
-            adapter.open_app(&package_name);
-            ()
-        })
-        .map_err(|err| {
-            error!("Failed: {}", err);
-            err
+/// Installs the bundle on the device and launches it, keeping the job entry of the device
+/// updated on each step
+fn install_bundle(
+    adapter: &dyn IAdapter,
+    bundle_path: &String,
+    ctx: &JobContext,
+) -> Result<(), InstallError> {
+    start_device(
+        adapter,
+        bundle_path,
+        ctx,
+        &format!("Installing {}", bundle_path),
+    )?;
+    let result = install_and_launch(adapter, bundle_path, bundle_path, ctx).map(|_| ());
+    finish_device(
+        adapter,
+        bundle_path,
+        ctx,
+        &result,
+        "Installed and launched app",
+    );
+    result
+}
+
+/// Runs the [UpgradePlan] of the job on the device, `bundle_path` being the new version
+fn upgrade_bundle(
+    adapter: &dyn IAdapter,
+    plan: &UpgradePlan,
+    bundle_path: &String,
+    ctx: &JobContext,
+) -> Result<(), InstallError> {
+    start_device(
+        adapter,
+        bundle_path,
+        ctx,
+        &format!("Upgrading {} to {}", &plan.from, bundle_path),
+    )?;
+    let result = upgrade_app(adapter, plan, bundle_path, ctx);
+    finish_device(
+        adapter,
+        bundle_path,
+        ctx,
+        &result,
+        &format!("Upgraded app, still running after {}s", plan.alive_seconds),
+    );
+    result
+}
+
+fn upgrade_app(
+    adapter: &dyn IAdapter,
+    plan: &UpgradePlan,
+    bundle_path: &str,
+    ctx: &JobContext,
+) -> Result<(), InstallError> {
+    let device = adapter.get_device();
+    let update_report = |f: &dyn Fn(&mut UpgradeReport)| {
+        registry::update_device(&ctx.job_id, &device.id, bundle_path, |d| {
+            f(d.upgrade.get_or_insert_with(UpgradeReport::default))
         });
+    };
+
+    // The previous version starts from scratch, so that its data is only the one of the setup flow
+    ctx.info(
+        device,
+        InstallStage::Install,
+        &format!("Installing the previous version {}", &plan.from),
+    );
+    let previous_ctx = JobContext {
+        install_mode: InstallMode::CleanInstall,
+        ..ctx.clone()
+    };
+    let previous = install_and_launch(adapter, &plan.from, bundle_path, &previous_ctx)?;
+    update_report(&|r| r.previous_launched = true);
+
+    if let Some(setup) = &plan.setup {
+        ctx.info(
+            device,
+            InstallStage::Setup,
+            &format!("Running setup flow {}", setup),
+        );
+        let result = run_setup_flow(device, setup, &previous, ctx);
+        update_report(&|r| r.setup_succeeded = Some(result.is_ok()));
+        result.map_err(|err| format!("Setup flow {} failed: {}", setup, err))?;
+    }
+    if ctx.is_cancelled() {
+        return Err(cancelled());
+    }
+
+    ctx.info(
+        device,
+        InstallStage::Install,
+        &format!("Upgrading to {}", bundle_path),
+    );
+    let upgrade_ctx = JobContext {
+        install_mode: InstallMode::UpdateInPlace,
+        ..ctx.clone()
+    };
+    let app = install_and_launch(adapter, bundle_path, bundle_path, &upgrade_ctx)?;
+    // Another package is installed next to the previous version, it's not an upgrade
+    if app.package_name != previous.package_name {
+        return Err(InstallError::from(format!(
+            "{} is not an upgrade of {}, the package names differ",
+            &app.package_name, &previous.package_name
+        )));
+    }
+    update_report(&|r| r.upgraded = true);
+
+    ctx.info(
+        device,
+        InstallStage::AliveCheck,
+        &format!(
+            "Checking that {} is still running in {}s",
+            &app.package_name, plan.alive_seconds
+        ),
+    );
+    for _ in 0..plan.alive_seconds {
+        if ctx.is_cancelled() {
+            return Err(cancelled());
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    let alive = adapter.is_app_running(&app, ctx)?;
+    update_report(&|r| r.alive = alive);
+    if !alive {
+        return Err(InstallError::from(format!(
+            "{} stopped running after the upgrade",
+            &app.package_name
+        )));
+    }
+    Ok(())
+}
+
+/// Runs the setup flow with the given name, from `SETUP_FLOWS_DIR`, against the app. The flow is
+/// called with the id of the device and the package name of the app
+fn run_setup_flow(
+    device: &Device,
+    setup: &str,
+    app: &InstalledApp,
+    ctx: &JobContext,
+) -> Result<(), String> {
+    let flow = setup_flow_path(setup)?;
+    ctx.exec(
+        device,
+        &flow.to_string_lossy(),
+        &[&device.id, &app.package_name],
+    )
+    .map(|_| ())
+}
+
+/// Returns the path of the setup flow with the given name, checking that it exists in
+/// `SETUP_FLOWS_DIR`
+pub fn setup_flow_path(setup: &str) -> Result<PathBuf, String> {
+    let directory = ENV_DATA
+        .lock()
+        .unwrap()
+        .setup_flows_dir
+        .clone()
+        .ok_or("SETUP_FLOWS_DIR is not set".to_string())?;
+    // Only the scripts in the directory can be run
+    if setup.is_empty() || setup.contains(['/', '\\']) || setup.starts_with('.') {
+        return Err(format!("Invalid setup flow name: {}", setup));
+    }
+    let path = Path::new(&directory).join(setup);
+    if !path.is_file() {
+        return Err(format!("Setup flow {} not found in {}", setup, directory));
+    }
+    Ok(path)
 }
 
 /// Installs the given bundle_path against all the devices connected
 ///
-/// The [OsType] is computed from the extension of the file given:
-/// - aab: [OsType::Android]
+/// The [OsType] is computed from the [BundleKind] of the file given:
+/// - aab/apk/apks and directories of split apks: [OsType::Android]
 /// - app/ipa: [OsType::Ios]
-pub fn install_bundle_all(bundle_path: &String) -> Result<(), String> {
+///
+/// Only the devices matching `filter` are targeted, whatever [OsType] it contains. The outcome on
+/// every device is reported to the job with the given `job_id`. The installs go through the
+/// [queue], so they wait for the other operations running on the same device. Nothing is installed
+/// once the job gets cancelled
+pub fn install_bundle_all(
+    bundle_path: &String,
+    job_id: &str,
+    filter: &DeviceFilter,
+) -> Result<(), String> {
+    run_bundle_all(bundle_path, job_id, filter, install_bundle)
+}
+
+/// Runs the [UpgradePlan] of the job against all the devices connected, `bundle_path` being the
+/// new version. Both versions have to target the same [OsType], the devices are selected like
+/// [install_bundle_all] does
+pub fn upgrade_bundle_all(
+    plan: UpgradePlan,
+    bundle_path: &String,
+    job_id: &str,
+    filter: &DeviceFilter,
+) -> Result<(), String> {
+    let from_kind = BundleKind::from_path(Path::new(&plan.from));
+    let to_kind = BundleKind::from_path(Path::new(bundle_path));
+    match (from_kind, to_kind) {
+        (Some(from), Some(to)) if from.os_type() == to.os_type() => {}
+        _ => {
+            return Err(format!(
+                "{} cannot be upgraded to {}",
+                &plan.from, bundle_path
+            ))
+        }
+    }
+
+    run_bundle_all(bundle_path, job_id, filter, move |adapter, path, ctx| {
+        upgrade_bundle(adapter, &plan, path, ctx)
+    })
+}
+
+/// Runs `task` for the bundle on every device matching the filter, through the [queue]
+fn run_bundle_all<F>(
+    bundle_path: &String,
+    job_id: &str,
+    filter: &DeviceFilter,
+    task: F,
+) -> Result<(), String>
+where
+    F: Fn(&dyn IAdapter, &String, &JobContext) -> Result<(), InstallError> + Send + Sync + 'static,
+{
     let file = Path::new(bundle_path);
     if !file.exists() {
         return Err("The given path does not exists".to_string());
     }
 
-    let extension = file.extension();
-    if extension.is_none() {
-        let filename = file
-            .file_name()
-            .map_or("no_name".to_string(), |s| s.to_str().unwrap().to_string());
-        error!("Missing extension on given file {}", &filename);
-        return Err(format!("Missing extension on given file {}", &filename));
-    }
-    let ext = extension.unwrap().to_str().unwrap();
-    if !vec!["aab", "ipa", "app", "apk"].contains(&ext) {
-        error!("Invalid file extension: {}", &ext);
-        return Err(format!("Invalid file extension: {}", &ext));
-    }
-
-    let os_device: Option<OsType>;
+    let kind = match BundleKind::from_path(file) {
+        Some(kind) if kind.is_installable() => kind,
+        _ => {
+            error!("Unsupported bundle: {}", bundle_path);
+            return Err(format!("Unsupported bundle: {}", bundle_path));
+        }
+    };
 
-    if ext == "aab" {
-        os_device = Some(OsType::Android);
-    } else {
-        os_device = Some(OsType::Ios);
+    let ctx = registry::context(job_id).ok_or(format!("Job {} not found", job_id))?;
+    if ctx.is_cancelled() {
+        info!("Job {} was cancelled, skipping {}", job_id, bundle_path);
+        return Ok(());
     }
 
-    let devices = find_devices(os_device);
+    // A device reconnecting is missing from the list for a while, so an empty list is retried too
+    let log = |msg: &str| {
+        warn!("[{}] {}", job_id, msg);
+        registry::append_log(job_id, LogLine::new(None, LogSource::Hub, msg));
+    };
+    let devices = retry(Operation::Discovery, &ctx, &log, || {
+        let devices = find_devices(&DeviceFilter {
+            os_type: Some(kind.os_type()),
+            ..filter.clone()
+        })?;
+        match devices.is_empty() {
+            true => Err(format!("No devices found to install {}", bundle_path)),
+            false => Ok(devices),
+        }
+    })?;
     info!("Found {} devices", devices.len());
 
-    let mut handles = Vec::<JoinHandle<()>>::new();
+    let task = Arc::new(task);
+    let mut installs = Vec::<Receiver<()>>::new();
 
     for device in devices.into_iter() {
+        registry::add_device(
+            job_id,
+            DeviceJob::new(
+                String::from(&device.get_device().id),
+                device.get_device_name(),
+                device.get_os_type(),
+                String::from(bundle_path),
+            ),
+        );
+
+        ctx.info(
+            device.get_device(),
+            InstallStage::Queued,
+            &format!("Queued with priority {}", ctx.priority),
+        );
+
         let temp_path = String::from(bundle_path);
-        let handle = thread::spawn(move || {
+        let temp_ctx = ctx.clone();
+        let temp_task = Arc::clone(&task);
+        let device_info = device.get_device().clone();
+        let install = queue::enqueue(&ctx, &device_info, bundle_path, move || {
+            let ctx = temp_ctx;
             info!(
                 "Installing against {} -> {}",
                 device.get_device_name(),
                 device.get_os_type().to_string()
             );
-            match install_bundle(device.as_ref(), &temp_path) {
+            match temp_task(device.as_ref(), &temp_path, &ctx) {
                 Ok(_) => info!("installed and ran app"),
                 Err(err) => {
                     error!("Failed to install and run app: {}", err);
                 }
             }
         });
-        handles.push(handle);
+        installs.push(install);
     }
 
-    for handle in handles {
-        handle.join().unwrap();
+    for install in installs {
+        _ = install.recv();
     }
 
-    return Ok(());
+    Ok(())
 }
@@ -0,0 +1,131 @@
+use std::process::Command;
+
+use log::warn;
+use serde::Deserialize;
+use strum_macros::EnumString;
+
+use crate::device_adapter::{
+    android::adb_client::AdbClient,
+    i_adapter::{version_from_sdk, DecodedDevice, Device, OsType},
+};
+
+use super::command_executor;
+
+/// Tool used to list the devices connected to the hub
+#[derive(Debug, Clone, Copy, PartialEq, EnumString)]
+pub enum DiscoveryBackend {
+    /// Asks the adb server for the android devices and uses `idb list-targets --json` for iOS
+    #[strum(serialize = "native")]
+    Native,
+    /// Uses `flutter devices --machine`, requires the Flutter SDK
+    #[strum(serialize = "flutter")]
+    Flutter,
+}
+
+/// Lists all the devices connected using the given backend
+pub fn discover_devices(backend: DiscoveryBackend) -> Result<Vec<Device>, String> {
+    match backend {
+        DiscoveryBackend::Flutter => flutter_devices(),
+        DiscoveryBackend::Native => {
+            let mut devices = adb_devices()?;
+            // idb is available only on macOS, so hubs without it just have no iOS devices
+            match idb_devices() {
+                Ok(ios_devices) => devices.extend(ios_devices),
+                Err(err) => warn!("Skipping iOS devices discovery: {}", err),
+            }
+            Ok(devices)
+        }
+    }
+}
+
+fn flutter_devices() -> Result<Vec<Device>, String> {
+    let output = Command::new("flutter")
+        .arg("devices")
+        .arg("--machine")
+        .output()
+        .map_err(|err| format!("Failed to run flutter devices: {}", err))?;
+
+    let json_content = String::from_utf8(output.stdout)
+        .map_err(|err| format!("The obtained string is not valid: {}", err))?;
+
+    let data: Vec<DecodedDevice> = serde_json::from_str::<Vec<DecodedDevice>>(&json_content)
+        .map_err(|err| format!("Invalid json from flutter devices: {}", err))?;
+
+    Ok(data.iter().map(Device::from_parsed).collect())
+}
+
+/// Lists the android devices through the adb server, skipping the devices that are offline or
+/// unauthorized
+fn adb_devices() -> Result<Vec<Device>, String> {
+    let client = AdbClient::from_env();
+
+    Ok(client
+        .devices()?
+        .into_iter()
+        .filter(|device| device.state == "device")
+        .map(|device| {
+            let emulator = device.serial.starts_with("emulator-")
+                || device
+                    .properties
+                    .get("product")
+                    .is_some_and(|product| product.starts_with("sdk_"));
+            let os_version = client
+                .shell(&device.serial, "getprop ro.build.version.release")
+                .map(|version| version.trim().to_string())
+                .unwrap_or_default();
+
+            Device {
+                name: device
+                    .properties
+                    .get("model")
+                    .map_or(String::from(&device.serial), |model| {
+                        model.replace('_', " ")
+                    }),
+                id: device.serial,
+                os_type: OsType::Android,
+                emulator,
+                os_version,
+            }
+        })
+        .collect())
+}
+
+/// Single target returned by `idb list-targets --json`
+#[derive(Debug, Deserialize)]
+struct IdbTarget {
+    name: String,
+    udid: String,
+    state: String,
+    #[serde(rename = "type")]
+    target_type: String,
+    os_version: Option<String>,
+}
+
+/// Parses the output of `idb list-targets --json`, which contains one json object per line.
+/// Only booted targets are returned
+fn idb_devices() -> Result<Vec<Device>, String> {
+    let output = command_executor::exec("idb", &["list-targets", "--json"])?;
+
+    Ok(output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str::<IdbTarget>(line) {
+            Ok(target) => Some(target),
+            Err(err) => {
+                warn!("Invalid target from idb {}: {}", line, err);
+                None
+            }
+        })
+        .filter(|target| target.state == "Booted")
+        .map(|target| Device {
+            name: target.name,
+            id: target.udid,
+            os_type: OsType::Ios,
+            emulator: target.target_type == "simulator",
+            os_version: target
+                .os_version
+                .map(|version| version_from_sdk(&version))
+                .unwrap_or_default(),
+        })
+        .collect())
+}
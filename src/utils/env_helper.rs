@@ -1,8 +1,13 @@
-use std::{path::Path, sync::Mutex};
+use std::{collections::HashMap, path::Path, str::FromStr, sync::Mutex, time::Duration};
 
 use dotenv::dotenv;
 use once_cell::sync::Lazy;
 
+use super::{
+    discovery::DiscoveryBackend,
+    retry::{Operation, RetryPolicy},
+};
+
 pub static ENV_DATA: Lazy<Mutex<EnvData>> = Lazy::new(|| Mutex::new(EnvData::load().unwrap()));
 
 /// Contains all the env data
@@ -13,8 +18,51 @@ pub struct EnvData {
     pub extract_output_dir: String,
     /// Directory in which the `/upload` endpoint saves the archives
     pub download_default_dir: String,
+    /// Maximum size in bytes of the body accepted by the `/upload` endpoint
+    pub max_upload_size: u64,
+    /// Tool used to find the connected devices
+    pub discovery_backend: DiscoveryBackend,
+    /// Address of the adb server, `127.0.0.1:5037` by default
+    pub adb_server_address: String,
+    /// Time after which the external commands are killed, unless they specify their own timeout
+    pub command_timeout: Duration,
+    /// Journal in which the jobs are stored, `{DOWNLOAD_DEFAULT_DIR}/jobs.jsonl` by default
+    pub job_history_file: String,
+    /// Number of finished jobs kept in memory and in the history, the oldest ones are dropped first
+    pub job_history_max_jobs: usize,
+    /// Directory in which the uploaded artifacts are stored by their SHA-256,
+    /// `{DOWNLOAD_DEFAULT_DIR}/artifacts` by default
+    pub artifact_store_dir: String,
+    /// Limits after which the least recently used artifacts are removed from the store
+    pub artifact_retention: RetentionPolicy,
+    /// Directory in which the apks built from the aabs are kept for each device spec,
+    /// `{DOWNLOAD_DEFAULT_DIR}/apks-cache` by default
+    pub apks_cache_dir: String,
+    /// Directory containing the scripts that upgrade jobs can run to seed the previous version of
+    /// the app, upgrade jobs with a setup flow are rejected when [None]
+    pub setup_flows_dir: Option<String>,
+    /// Policies overriding the [Operation::default_policy] of the device operations
+    pub retry_policies: HashMap<Operation, RetryPolicy>,
+}
+
+/// Each limit is disabled when [None]
+pub struct RetentionPolicy {
+    /// Artifacts not uploaded or installed for longer than this are removed
+    pub max_age: Option<Duration>,
+    pub max_count: Option<usize>,
+    /// Total size in bytes of the stored artifacts
+    pub max_total_size: Option<u64>,
 }
 
+/// Used when `MAX_UPLOAD_SIZE` is not set: 2GiB
+const DEFAULT_MAX_UPLOAD_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Used when `COMMAND_TIMEOUT` is not set: 10 minutes
+const DEFAULT_COMMAND_TIMEOUT: u64 = 600;
+
+/// Used when `JOB_HISTORY_MAX_JOBS` is not set
+const DEFAULT_JOB_HISTORY_MAX_JOBS: usize = 1000;
+
 pub struct AndroidConfig {
     pub keystore_path: String,
     pub keystore_alias: String,
@@ -39,6 +87,61 @@ impl EnvData {
         let download_default_dir =
             dotenv::var("DOWNLOAD_DEFAULT_DIR").map_err(|err| err.to_string())?;
 
+        let max_upload_size = match dotenv::var("MAX_UPLOAD_SIZE") {
+            Ok(size) => size
+                .parse::<u64>()
+                .map_err(|err| format!("Invalid MAX_UPLOAD_SIZE {}: {}", size, err))?,
+            Err(_) => DEFAULT_MAX_UPLOAD_SIZE,
+        };
+
+        let discovery_backend = match dotenv::var("DEVICE_DISCOVERY") {
+            Ok(backend) => DiscoveryBackend::from_str(&backend)
+                .map_err(|err| format!("Invalid DEVICE_DISCOVERY {}: {}", backend, err))?,
+            Err(_) => DiscoveryBackend::Native,
+        };
+
+        let adb_server_address =
+            dotenv::var("ADB_SERVER_ADDRESS").unwrap_or("127.0.0.1:5037".to_string());
+
+        let command_timeout = match dotenv::var("COMMAND_TIMEOUT") {
+            Ok(timeout) => timeout
+                .parse::<u64>()
+                .map_err(|err| format!("Invalid COMMAND_TIMEOUT {}: {}", timeout, err))?,
+            Err(_) => DEFAULT_COMMAND_TIMEOUT,
+        };
+
+        let job_history_file = dotenv::var("JOB_HISTORY_FILE")
+            .unwrap_or(format!("{}/jobs.jsonl", &download_default_dir));
+        let job_history_max_jobs =
+            optional_var::<usize>("JOB_HISTORY_MAX_JOBS")?.unwrap_or(DEFAULT_JOB_HISTORY_MAX_JOBS);
+
+        let artifact_store_dir = dotenv::var("ARTIFACT_STORE_DIR")
+            .unwrap_or(format!("{}/artifacts", &download_default_dir));
+
+        let apks_cache_dir = dotenv::var("APKS_CACHE_DIR")
+            .unwrap_or(format!("{}/apks-cache", &download_default_dir));
+
+        let setup_flows_dir = optional_var::<String>("SETUP_FLOWS_DIR")?;
+
+        let mut retry_policies = HashMap::new();
+        for operation in [
+            Operation::Discovery,
+            Operation::ApkBuild,
+            Operation::Install,
+            Operation::Launch,
+        ] {
+            if let Some(policy) = optional_var::<RetryPolicy>(&operation.env_var())? {
+                retry_policies.insert(operation, policy);
+            }
+        }
+
+        let artifact_retention = RetentionPolicy {
+            max_age: optional_var::<u64>("ARTIFACT_MAX_AGE_DAYS")?
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            max_count: optional_var::<usize>("ARTIFACT_MAX_COUNT")?,
+            max_total_size: optional_var::<u64>("ARTIFACT_MAX_TOTAL_SIZE")?,
+        };
+
         if !Path::new(&extract_output_dir).is_absolute() {
             panic!("EXTRACT_DEFAULT_DIR must be absolute");
         }
@@ -50,6 +153,17 @@ impl EnvData {
         Ok(EnvData {
             extract_output_dir,
             download_default_dir,
+            max_upload_size,
+            discovery_backend,
+            adb_server_address,
+            command_timeout: Duration::from_secs(command_timeout),
+            job_history_file,
+            job_history_max_jobs,
+            artifact_store_dir,
+            artifact_retention,
+            apks_cache_dir,
+            setup_flows_dir,
+            retry_policies,
             android_config: AndroidConfig {
                 keystore_path: android_keystore_path,
                 keystore_alias: android_keystore_alias,
@@ -57,4 +171,27 @@ impl EnvData {
             },
         })
     }
+
+    /// Returns the policy set through `RETRY_{OPERATION}`, or the default one
+    pub fn retry_policy(&self, operation: Operation) -> RetryPolicy {
+        self.retry_policies
+            .get(&operation)
+            .copied()
+            .unwrap_or(operation.default_policy())
+    }
+}
+
+/// Parses the env variable with the given name, returns [None] if it's not set
+fn optional_var<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match dotenv::var(name) {
+        Ok(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|err| format!("Invalid {} {}: {}", name, value, err)),
+        Err(_) => Ok(None),
+    }
 }
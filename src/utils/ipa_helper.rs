@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, Cursor},
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use log::info;
+use once_cell::sync::Lazy;
+use plist::{Dictionary, Value};
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
+
+use super::zip_helper;
+
+/// `Info.plist` of the bundles already read, indexed by their path, so that every device installing
+/// the same bundle shares it
+static INFO_PLISTS: Lazy<Mutex<HashMap<String, CachedPlist>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+type PlistResult = Result<Arc<Dictionary>, String>;
+
+struct CachedPlist {
+    /// Modification time of the bundle when it was read, a bundle replaced at the same path is
+    /// read again
+    modified: Option<SystemTime>,
+    plist: Arc<OnceLock<PlistResult>>,
+}
+
+/// Returns the `Info.plist` of the `.app` directory or `.ipa` file. The plist of an ipa is read
+/// straight from the archive, without extracting it.
+///
+/// Each bundle is read only once: concurrent callers wait for the first one and get the same plist
+pub fn read_info_plist(bundle_path: &str) -> PlistResult {
+    let modified = fs::metadata(bundle_path)
+        .map_err(|err| format!("Failed to read {}: {}", bundle_path, err))?
+        .modified()
+        .ok();
+
+    let plist = {
+        let mut plists = INFO_PLISTS.lock().unwrap();
+        match plists.get(bundle_path) {
+            Some(cached) if cached.modified == modified => Arc::clone(&cached.plist),
+            _ => {
+                // Bundles extracted from zip archives are removed with their job
+                plists.retain(|path, _| Path::new(path).exists());
+                let plist = Arc::new(OnceLock::new());
+                plists.insert(
+                    String::from(bundle_path),
+                    CachedPlist {
+                        modified,
+                        plist: Arc::clone(&plist),
+                    },
+                );
+                plist
+            }
+        }
+    };
+
+    plist
+        .get_or_init(|| {
+            info!("Reading Info.plist of {}", bundle_path);
+            let content = read_app_file(bundle_path, "Info.plist")?;
+            Value::from_reader(Cursor::new(content))
+                .map_err(|err| format!("Invalid Info.plist in {}: {}", bundle_path, err))?
+                .into_dictionary()
+                .map(Arc::new)
+                .ok_or(format!(
+                    "The Info.plist of {} is not a dictionary",
+                    bundle_path
+                ))
+        })
+        .clone()
+}
+
+/// Reads a file at the root of the app: inside the `.app` directory or inside `Payload/*.app/` for
+/// `.ipa` files
+fn read_app_file(bundle_path: &str, name: &str) -> Result<Vec<u8>, String> {
+    let bundle = Path::new(bundle_path);
+    if bundle.is_dir() {
+        let path = bundle.join(name);
+        return fs::read(&path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err));
+    }
+
+    let file =
+        File::open(bundle).map_err(|err| format!("Failed to open {}: {}", bundle_path, err))?;
+    let mut archive = ZipArchive::new(BufReader::new(file))
+        .map_err(|err| format!("Invalid archive {}: {}", bundle_path, err))?;
+
+    // Only the central directory is read to find the file, other entries are never decompressed
+    let entry_name = archive
+        .file_names()
+        .find(|entry| {
+            let components = entry.split('/').collect::<Vec<&str>>();
+            matches!(components[..], ["Payload", app, file] if app.ends_with(".app") && file == name)
+        })
+        .map(String::from)
+        .ok_or(format!("No Payload/*.app/{} found in {}", name, bundle_path))?;
+
+    zip_helper::read_entry(&mut archive, &entry_name)
+}
+
+/// Returns the value of a string key of the plist
+pub fn string_value(plist: &Dictionary, key: &str) -> Option<String> {
+    plist
+        .get(key)
+        .and_then(|value| value.as_string())
+        .map(String::from)
+}
+
+/// Returns the `CFBundleIdentifier` of the plist
+pub fn bundle_identifier(plist: &Dictionary) -> Result<String, String> {
+    string_value(plist, "CFBundleIdentifier").ok_or("Missing CFBundleIdentifier".to_string())
+}
+
+/// Returns the SHA-256 of the first developer certificate in the `embedded.mobileprovision` of the
+/// app, [None] if the app has no provisioning profile (ex. simulator builds)
+pub fn certificate_sha256(bundle_path: &str) -> Option<String> {
+    let profile = read_app_file(bundle_path, "embedded.mobileprovision").ok()?;
+
+    // The profile is a plist wrapped in a CMS signature, the plist can be read as it is
+    let start = find(&profile, b"<?xml")?;
+    let end = start + find(&profile[start..], b"</plist>")? + b"</plist>".len();
+    let plist = Value::from_reader_xml(&profile[start..end]).ok()?;
+
+    let certificate = plist
+        .as_dictionary()?
+        .get("DeveloperCertificates")?
+        .as_array()?
+        .first()?
+        .as_data()?;
+    Some(format!("{:x}", Sha256::digest(certificate)))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -1,5 +1,11 @@
+pub mod android_manifest;
 pub mod apks_helper;
 pub mod args;
+pub mod bundle_kind;
 pub mod command_executor;
 pub mod commands;
+pub mod discovery;
 pub mod env_helper;
+pub mod ipa_helper;
+pub mod retry;
+pub mod zip_helper;
@@ -0,0 +1,212 @@
+use std::{
+    cmp::min,
+    fmt::Display,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use strum::Display;
+use strum_macros::EnumString;
+
+use crate::{device_adapter::install_error::InstallErrorCode, jobs::context::JobContext};
+
+use super::env_helper::ENV_DATA;
+
+/// Device operations that get retried when they fail for a transient reason, each one with its
+/// own [RetryPolicy]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Operation {
+    Discovery,
+    ApkBuild,
+    Install,
+    Launch,
+}
+
+impl Operation {
+    /// Name of the env variable overriding the policy, ex. `RETRY_APK_BUILD`
+    pub fn env_var(&self) -> String {
+        format!("RETRY_{}", self.to_string().to_uppercase())
+    }
+
+    pub fn default_policy(&self) -> RetryPolicy {
+        let (max_attempts, initial_backoff, max_backoff) = match self {
+            Operation::Discovery => (3, 1000, 10000),
+            Operation::ApkBuild => (2, 2000, 10000),
+            Operation::Install => (3, 2000, 30000),
+            Operation::Launch => (2, 1000, 5000),
+        };
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(initial_backoff),
+            max_backoff: Duration::from_millis(max_backoff),
+        }
+    }
+}
+
+/// How many times an [Operation] is tried, waiting between the attempts a backoff that doubles
+/// every time up to `max_backoff`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Includes the first attempt, `1` disables the retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Time to wait after the failed attempt with the given number, starting from `1`
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        min(
+            self.initial_backoff.saturating_mul(factor),
+            self.max_backoff,
+        )
+    }
+}
+
+/// Parses `{attempts}:{initial backoff ms}:{max backoff ms}` (ex. `3:2000:30000`)
+impl FromStr for RetryPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts = value
+            .split(':')
+            .map(|part| part.trim().parse::<u64>().map_err(|err| err.to_string()))
+            .collect::<Result<Vec<u64>, String>>()?;
+        let [attempts, initial_backoff, max_backoff] = parts[..] else {
+            return Err("Expected attempts:initial_backoff_ms:max_backoff_ms".to_string());
+        };
+        if attempts == 0 {
+            return Err("At least one attempt is needed".to_string());
+        }
+        Ok(RetryPolicy {
+            max_attempts: u32::try_from(attempts).map_err(|err| err.to_string())?,
+            initial_backoff: Duration::from_millis(initial_backoff),
+            max_backoff: Duration::from_millis(max_backoff),
+        })
+    }
+}
+
+/// Runs `f` until it succeeds, following the [RetryPolicy] of the operation. Only the errors
+/// classified as transient (see [InstallErrorCode::is_transient]) are retried, and only while the
+/// job is not cancelled.
+///
+/// Each failed attempt, and the success that follows, is reported through `log`, which writes it
+/// in the job log. The operations must not be nested, each attempt would run all the attempts of
+/// the inner one
+pub fn retry<T, E, F>(
+    operation: Operation,
+    ctx: &JobContext,
+    log: &dyn Fn(&str),
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+    E: Display,
+{
+    let policy = ENV_DATA.lock().unwrap().retry_policy(operation);
+    let mut attempt = 1;
+    loop {
+        let err = match f() {
+            Ok(value) => {
+                if attempt > 1 {
+                    log(&format!("{} succeeded on attempt {}", operation, attempt));
+                }
+                return Ok(value);
+            }
+            Err(err) => err,
+        };
+
+        let code = InstallErrorCode::classify(&err.to_string());
+        if !code.is_transient() || attempt >= policy.max_attempts || ctx.is_cancelled() {
+            if attempt > 1 {
+                log(&format!("{} failed after {} attempts", operation, attempt));
+            }
+            return Err(err);
+        }
+
+        let backoff = policy.backoff(attempt);
+        log(&format!(
+            "{} failed on attempt {}/{} ({:?}), retrying in {}ms: {}",
+            operation,
+            attempt,
+            policy.max_attempts,
+            code,
+            backoff.as_millis(),
+            err
+        ));
+        if !wait(backoff, ctx) {
+            return Err(err);
+        }
+        attempt += 1;
+    }
+}
+
+/// Sleeps for `duration`, returns `false` if the job got cancelled in the meantime
+fn wait(duration: Duration, ctx: &JobContext) -> bool {
+    let deadline = Instant::now() + duration;
+    while !ctx.is_cancelled() {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(min(deadline - now, Duration::from_millis(200)));
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32, initial_backoff: u64, max_backoff: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(initial_backoff),
+            max_backoff: Duration::from_millis(max_backoff),
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        let cases = [
+            ("3:2000:30000", Ok(policy(3, 2000, 30000))),
+            (" 1 : 0 : 0 ", Ok(policy(1, 0, 0))),
+            ("0:1000:5000", Err(())),
+            ("3:2000", Err(())),
+            ("3:2000:30000:1", Err(())),
+            ("three:2000:30000", Err(())),
+            ("-1:2000:30000", Err(())),
+            ("4294967296:2000:30000", Err(())),
+            ("", Err(())),
+        ];
+        for (value, expected) in cases {
+            let parsed = value.parse::<RetryPolicy>().map_err(|_| ());
+            assert_eq!(parsed, expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_max() {
+        let policy = policy(5, 2000, 30000);
+        let cases = [(1, 2000), (2, 4000), (3, 8000), (4, 16000), (5, 30000)];
+        for (attempt, backoff) in cases {
+            assert_eq!(
+                policy.backoff(attempt),
+                Duration::from_millis(backoff),
+                "attempt {}",
+                attempt
+            );
+        }
+    }
+
+    #[test]
+    fn saturates_the_backoff_of_late_attempts() {
+        let policy = policy(u32::MAX, 2000, 30000);
+        assert_eq!(policy.backoff(0), Duration::from_millis(2000));
+        assert_eq!(policy.backoff(64), Duration::from_millis(30000));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(30000));
+    }
+}
@@ -0,0 +1,30 @@
+use std::io::{Read, Seek};
+
+use zip::ZipArchive;
+
+/// Largest entry decompressed in memory: 512MiB. The sizes written in the archive are not checked,
+/// a small archive can expand to any size (zip bomb)
+const MAX_ENTRY_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Decompresses the entry of the archive in memory, fails if it's larger than [MAX_ENTRY_SIZE]
+pub fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Vec<u8>, String> {
+    let entry = archive
+        .by_name(name)
+        .map_err(|err| format!("Failed to find {}: {}", name, err))?;
+    let mut content = Vec::new();
+    // One more byte than the limit is read to tell an entry of exactly the limit from a larger one
+    entry
+        .take(MAX_ENTRY_SIZE + 1)
+        .read_to_end(&mut content)
+        .map_err(|err| format!("Failed to read {}: {}", name, err))?;
+    if content.len() as u64 > MAX_ENTRY_SIZE {
+        return Err(format!(
+            "{} is larger than {} bytes once decompressed",
+            name, MAX_ENTRY_SIZE
+        ));
+    }
+    Ok(content)
+}